@@ -4,7 +4,7 @@ fn main() {
 
   // generate rust bindings from protobuf IDL
   let mut config = prost_build::Config::new();
-  config.bytes(&["."]);
+  config.bytes(["."]);
   config
     .compile_protos(&["src/network/episub/rpc.proto"], &["src"])
     .unwrap();
@@ -0,0 +1,318 @@
+mod redelivery;
+
+pub use redelivery::RedeliveryConfig;
+use {
+  crate::{
+    primitives::{Addressable, Message},
+    rpc::{Session, SessionClosed, SessionId, SubscriptionId},
+    storage::{self, PersistentStorage},
+  },
+  crossbeam::queue::SegQueue,
+  dashmap::DashMap,
+  futures::Stream,
+  multihash::Multihash,
+  redelivery::{Redelivery, Retry},
+  std::{collections::HashMap, task::Poll, time::Duration},
+  thiserror::Error,
+  tracing::warn,
+};
+
+pub enum MessageBusEvent {
+  /// A recipient has acknowledged receiving a message on a topic.
+  MessageDelivered(Multihash, Multihash),
+  SubscriptionCreated(Multihash),
+  SubscriptionDropped(Multihash),
+}
+
+#[derive(Error, Debug)]
+pub enum SendError {
+  #[error("{0}")]
+  Session(#[from] SessionClosed),
+
+  #[error("Storage Error: {0}")]
+  Storage(#[from] storage::Error),
+
+  #[error("Session is not subscribed to topic {0:?}")]
+  NotSubscribed(Multihash),
+
+  #[error("Session has no subscription {0}")]
+  UnknownSubscription(SubscriptionId),
+
+  #[error("Message {0:?} is not in the mailbox")]
+  UnknownMessage(Multihash),
+}
+
+pub struct MessageBus {
+  topics: DashMap<Multihash, HashMap<SubscriptionId, Session>>,
+  storage: PersistentStorage,
+  redelivery: Redelivery,
+  events_out: SegQueue<MessageBusEvent>,
+}
+
+impl MessageBus {
+  pub fn new(storage: PersistentStorage, redelivery: RedeliveryConfig) -> Self {
+    Self {
+      storage,
+      redelivery: Redelivery::new(redelivery),
+      topics: DashMap::new(),
+      events_out: SegQueue::new(),
+    }
+  }
+
+  /// Registers a new subscriber on a topic.
+  ///
+  /// Before any live traffic reaches the subscriber, all messages in the
+  /// mailbox for this topic that were not acknowledged yet are replayed
+  /// to it in the order they arrived at this node.
+  pub fn create_subscription(
+    &self,
+    topic: Multihash,
+    id: SubscriptionId,
+    session: Session,
+  ) -> Result<(), SendError> {
+    for message in self.storage.messages(&topic)? {
+      session.notify(id, &message)?;
+      self.redelivery.schedule(topic, message.multihash());
+    }
+
+    let mut subscribers = self.topics.entry(topic).or_default();
+    subscribers.insert(id, session);
+
+    // other nodes only need to know that this node is hosting the topic,
+    // additional subscribers on the same topic don't change that.
+    if subscribers.len() == 1 {
+      self
+        .events_out
+        .push(MessageBusEvent::SubscriptionCreated(topic));
+    }
+
+    Ok(())
+  }
+
+  /// Occurs when the session of a subscriber can no longer
+  /// be written to.
+  pub fn drop_subscription(&self, topic: Multihash, id: SubscriptionId) {
+    self.remove_subscribers(topic, |sub, _| *sub == id);
+  }
+
+  /// Removes a subscription that was created through a given client
+  /// session, other subscriptions on the same topic are kept.
+  pub fn drop_session_subscription(
+    &self,
+    id: SubscriptionId,
+    session: SessionId,
+  ) -> Result<(), SendError> {
+    let topic = self
+      .topics
+      .iter()
+      .find_map(|subscribers| match subscribers.get(&id) {
+        Some(s) if s.id() == session => Some(*subscribers.key()),
+        _ => None,
+      })
+      .ok_or(SendError::UnknownSubscription(id))?;
+
+    self.drop_subscription(topic, id);
+    Ok(())
+  }
+
+  /// Removes all subscriptions created through a client session.
+  /// Occurs when the session connection is closed.
+  pub fn drop_session(&self, session: SessionId) {
+    self.topics.retain(|topic, subscribers| {
+      subscribers.retain(|_, s| s.id() != session);
+      if subscribers.is_empty() {
+        self
+          .events_out
+          .push(MessageBusEvent::SubscriptionDropped(*topic));
+      }
+      !subscribers.is_empty()
+    });
+  }
+
+  /// Called when a client confirms that it has processed a message
+  /// delivered on a topic it has subscribed to through its session.
+  ///
+  /// The message is removed from the local mailbox and the rest of the
+  /// network is informed so other nodes can drop their copies as well.
+  pub fn acknowledge_message(
+    &self,
+    topic: Multihash,
+    hash: Multihash,
+    session: SessionId,
+  ) -> Result<(), SendError> {
+    let subscribed = self
+      .topics
+      .get(&topic)
+      .map(|subscribers| subscribers.values().any(|s| s.id() == session))
+      .unwrap_or(false);
+    if !subscribed {
+      return Err(SendError::NotSubscribed(topic));
+    }
+
+    // only messages that are still waiting for an acknowledgement on this
+    // topic can be acknowledged, otherwise a client could erase mailboxes
+    // on other nodes by acknowledging arbitrary hashes.
+    if self.storage.message(&topic, &hash)?.is_none() {
+      return Err(SendError::UnknownMessage(hash));
+    }
+
+    self.redelivery.cancel(&hash);
+    self.storage.remove_message(&topic, &hash)?;
+    self
+      .events_out
+      .push(MessageBusEvent::MessageDelivered(topic, hash));
+
+    Ok(())
+  }
+
+  /// Called when some node ACKs delivering a message to a subscripion
+  /// it manages.
+  pub fn drop_message(&self, hash: &Multihash) -> Result<(), SendError> {
+    // no need to retry it any longer
+    self.redelivery.cancel(hash);
+    Ok(self.storage.remove_message_by_hash(hash)?)
+  }
+
+  /// Called whenever a message is gossiped through P2P and reaches the bus.
+  ///
+  /// The message is placed in the mailbox until either it expires or one of
+  /// the recipients acknowledges it. If the targeted topic is maintained by
+  /// this node, it is also immediately delivered to all its subscribers,
+  /// otherwise it waits for a subscription with the target topic to be
+  /// created.
+  pub fn send_message(&self, message: Message) -> Result<(), SendError> {
+    // keep the message around until the recipient confirms it, a client
+    // that disconnects before acknowledging gets it again when it comes
+    // back.
+    self.store(&message)?;
+
+    if self.notify_subscribers(&message) {
+      // retry until one of the recipients acknowledges it
+      self.redelivery.schedule(message.topic, message.multihash());
+    }
+
+    Ok(())
+  }
+
+  /// Sends a message to all subscriptions on its topic managed by this
+  /// node, and returns true if it reached at least one of them.
+  fn notify_subscribers(&self, message: &Message) -> bool {
+    let mut delivered = false;
+    let mut broken = vec![];
+    if let Some(subscribers) = self.topics.get(&message.topic) {
+      for (id, session) in subscribers.iter() {
+        match session.notify(*id, message) {
+          Ok(()) => delivered = true,
+          Err(e) => {
+            warn!("Failed to deliver message to {:?}: {e}", message.topic);
+            broken.push(*id);
+          }
+        }
+      }
+    }
+
+    // stop routing messages to subscribers with broken sockets
+    for id in broken {
+      self.drop_subscription(message.topic, id);
+    }
+
+    delivered
+  }
+
+  /// Acts on a redelivery attempt that became due.
+  fn retry(&self, retry: Retry) -> Result<(), SendError> {
+    match retry {
+      Retry::Redeliver { topic, hash } => {
+        match self.storage.message(&topic, &hash)? {
+          Some(message) => {
+            if !self.notify_subscribers(&message) {
+              // nobody to deliver to, the next subscription on the
+              // topic gets it replayed and resumes the retries.
+              let ttl = Duration::from_secs(message.ttl.into());
+              self.redelivery.suspend(&hash, ttl);
+            }
+          }
+          // expired or acknowledged through another node
+          None => self.redelivery.cancel(&hash),
+        }
+      }
+      Retry::Exhausted { topic, hash } => {
+        warn!(
+          "Message {hash:?} was never acknowledged, moving to dead letters"
+        );
+        self.storage.dead_letter_message(&topic, &hash)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Removes all subscribers on a topic that match the predicate.
+  /// The topic is removed from the bus once its last subscriber is gone.
+  fn remove_subscribers(
+    &self,
+    topic: Multihash,
+    mut predicate: impl FnMut(&SubscriptionId, &Session) -> bool,
+  ) {
+    let removed = self
+      .topics
+      .remove_if_mut(&topic, |_, subscribers| {
+        subscribers.retain(|id, s| !predicate(id, s));
+        subscribers.is_empty()
+      })
+      .is_some();
+
+    if removed {
+      self
+        .events_out
+        .push(MessageBusEvent::SubscriptionDropped(topic));
+    }
+  }
+
+  /// Keeps the message in the mailbox until a subscriber acknowledges it
+  /// or its TTL runs out.
+  fn store(&self, message: &Message) -> Result<(), SendError> {
+    Ok(self.storage.store_message(message)?)
+  }
+}
+
+impl Unpin for MessageBus {}
+impl Stream for MessageBus {
+  type Item = MessageBusEvent;
+
+  fn poll_next(
+    self: std::pin::Pin<&mut Self>,
+    cx: &mut std::task::Context<'_>,
+  ) -> std::task::Poll<Option<Self::Item>> {
+    while let Poll::Ready(retry) = self.redelivery.poll_due(cx) {
+      if let Err(e) = self.retry(retry) {
+        warn!("Failed to redeliver message: {e}");
+      }
+    }
+
+    if let Some(event) = self.events_out.pop() {
+      return Poll::Ready(Some(event));
+    }
+    Poll::Pending
+  }
+}
+
+macro_rules! handle {
+  ($event:ident, $network: ident) => {
+    match $event {
+      MessageBusEvent::MessageDelivered(topic, hash) => {
+        info!("Message {hash:?} acknowledged");
+        $network.gossip_ack(topic, hash)?;
+      }
+      MessageBusEvent::SubscriptionCreated(topic) => {
+        info!("topic {topic:?} created");
+        $network.gossip_subscription(topic)?;
+      }
+      MessageBusEvent::SubscriptionDropped(topic) => {
+        info!("topic {topic:?} dropped");
+        $network.gossip_unsubscription(topic)?;
+      }
+    }
+  };
+}
+
+pub(crate) use handle;
@@ -0,0 +1,199 @@
+use {
+  multihash::Multihash,
+  std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    future::Future,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll, Waker},
+    time::Duration,
+  },
+  tokio::time::{sleep_until, Instant, Sleep},
+};
+
+/// Controls how messages that were delivered to local subscribers, but
+/// never acknowledged, are delivered again.
+#[derive(Debug, Clone)]
+pub struct RedeliveryConfig {
+  /// The delay before the first redelivery of a message,
+  /// it doubles with every further attempt.
+  pub initial_backoff: Duration,
+
+  /// Upper bound on the delay between two redeliveries.
+  pub max_backoff: Duration,
+
+  /// The number of redeliveries after which an unacknowledged
+  /// message is moved to the dead-letter tree.
+  pub max_attempts: u32,
+}
+
+/// A scheduled redelivery that became due.
+#[derive(Debug)]
+pub(super) enum Retry {
+  /// The message should be sent again to all subscribers on its topic.
+  Redeliver { topic: Multihash, hash: Multihash },
+
+  /// The message was not acknowledged after all attempts.
+  Exhausted { topic: Multihash, hash: Multihash },
+}
+
+/// An unacknowledged message tracked by the scheduler.
+struct Pending {
+  topic: Multihash,
+
+  /// Number of redeliveries so far.
+  attempts: u32,
+
+  /// When the next attempt is due, messages are not retried
+  /// while their topic has no subscribers on this node.
+  due: Option<Instant>,
+
+  /// When a suspended message is forgotten, by then it has expired
+  /// from the mailbox and can't be delivered again anyway.
+  expires: Option<Instant>,
+}
+
+#[derive(Default)]
+struct State {
+  pending: HashMap<Multihash, Pending>,
+
+  /// Upcoming attempts ordered by their due time. Entries
+  /// that no longer match their pending record are stale
+  /// and are skipped when they come up.
+  queue: BinaryHeap<Reverse<(Instant, Multihash)>>,
+  timer: Option<Pin<Box<Sleep>>>,
+  waker: Option<Waker>,
+}
+
+/// Keeps track of messages delivered to local subscribers that are
+/// waiting for an acknowledgement, and decides when they should be
+/// delivered again.
+///
+/// The scheduler doesn't run on its own, it is driven by the message
+/// bus stream that polls it for due attempts.
+pub(super) struct Redelivery {
+  config: RedeliveryConfig,
+  state: Mutex<State>,
+}
+
+impl Redelivery {
+  pub fn new(config: RedeliveryConfig) -> Self {
+    Self {
+      config,
+      state: Mutex::new(State::default()),
+    }
+  }
+
+  /// Called whenever a message is delivered to a subscriber.
+  ///
+  /// Messages seen for the first time get their first attempt scheduled,
+  /// messages that were put on hold because their topic lost all its
+  /// subscribers resume backing off from where they stopped.
+  pub fn schedule(&self, topic: Multihash, hash: Multihash) {
+    let mut state = self.state.lock().unwrap();
+    let attempts = match state.pending.get(&hash) {
+      Some(Pending { due: Some(_), .. }) => return, // already scheduled
+      Some(pending) => pending.attempts,
+      None => 0,
+    };
+
+    let due = Instant::now() + self.backoff(attempts);
+    state.pending.insert(hash, Pending {
+      topic,
+      attempts,
+      due: Some(due),
+      expires: None,
+    });
+    state.queue.push(Reverse((due, hash)));
+
+    // the new attempt might be due earlier than the armed timer
+    if let Some(waker) = state.waker.take() {
+      waker.wake();
+    }
+  }
+
+  /// Puts a message on hold until it is delivered again to a new
+  /// subscription. The message is forgotten if that doesn't happen
+  /// within `ttl`, as it is removed from the mailbox by then.
+  pub fn suspend(&self, hash: &Multihash, ttl: Duration) {
+    let mut state = self.state.lock().unwrap();
+    let expires = Instant::now() + ttl;
+    if let Some(pending) = state.pending.get_mut(hash) {
+      pending.due = None;
+      pending.expires = Some(expires);
+      state.queue.push(Reverse((expires, *hash)));
+      if let Some(waker) = state.waker.take() {
+        waker.wake();
+      }
+    }
+  }
+
+  /// Stops retrying a message, either because it was
+  /// acknowledged or because it is no longer stored.
+  pub fn cancel(&self, hash: &Multihash) {
+    self.state.lock().unwrap().pending.remove(hash);
+  }
+
+  /// Returns the next attempt that is due, if any.
+  ///
+  /// The next redelivery is scheduled right away, so a message is retried
+  /// until it is cancelled or it runs out of attempts.
+  pub fn poll_due(&self, cx: &mut Context<'_>) -> Poll<Retry> {
+    let mut state = self.state.lock().unwrap();
+    let state = &mut *state;
+
+    while let Some(Reverse((due, hash))) = state.queue.peek().cloned() {
+      if due > Instant::now() {
+        let timer = state
+          .timer
+          .get_or_insert_with(|| Box::pin(sleep_until(due)));
+        if timer.deadline() != due {
+          timer.as_mut().reset(due);
+        }
+        if timer.as_mut().poll(cx).is_ready() {
+          // elapsed in the meantime, look at the queue again
+          continue;
+        }
+        break;
+      }
+
+      state.queue.pop();
+      let pending = match state.pending.get_mut(&hash) {
+        Some(pending) if pending.due == Some(due) => pending,
+        Some(pending)
+          if pending.due.is_none() && pending.expires == Some(due) =>
+        {
+          state.pending.remove(&hash);
+          continue; // suspended until its message expired
+        }
+        _ => continue, // stale entry
+      };
+
+      let topic = pending.topic;
+      if pending.attempts >= self.config.max_attempts {
+        state.pending.remove(&hash);
+        return Poll::Ready(Retry::Exhausted { topic, hash });
+      }
+
+      pending.attempts += 1;
+      let next = Instant::now() + self.backoff(pending.attempts);
+      pending.due = Some(next);
+      state.queue.push(Reverse((next, hash)));
+      return Poll::Ready(Retry::Redeliver { topic, hash });
+    }
+
+    state.waker = Some(cx.waker().clone());
+    Poll::Pending
+  }
+
+  /// The delay before the next attempt, doubles with every attempt
+  /// until it reaches the configured maximum.
+  fn backoff(&self, attempts: u32) -> Duration {
+    self
+      .config
+      .initial_backoff
+      .saturating_mul(1u32.checked_shl(attempts).unwrap_or(u32::MAX))
+      .min(self.config.max_backoff)
+  }
+}
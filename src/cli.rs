@@ -1,10 +1,15 @@
 use {
-  crate::primitives::{Keypair, Pubkey},
+  crate::{
+    bus::RedeliveryConfig,
+    network::{Seed, ShardConfig},
+    primitives::{Keypair, Pubkey},
+  },
   clap::Parser,
-  libp2p::{multiaddr::Protocol, Multiaddr},
+  libp2p::{multiaddr::Protocol, Multiaddr, PeerId},
   std::{
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     path::PathBuf,
+    time::Duration,
   },
 };
 
@@ -31,9 +36,10 @@ pub struct CliOpts {
 
   #[clap(
     long,
-    help = "address of a known peer to bootstrap p2p networking from"
+    help = "address, multiaddress or hostname of a known peer to bootstrap \
+            p2p networking from"
   )]
-  peer: Vec<SocketAddr>,
+  peer: Vec<Seed>,
 
   #[clap(long, help = "listen address of the node", default_value = "0.0.0.0")]
   addr: Vec<IpAddr>,
@@ -44,6 +50,13 @@ pub struct CliOpts {
   #[clap(long, help = "port on which RPC API service is exposed")]
   rpc: Option<u16>,
 
+  #[clap(
+    long,
+    help = "port on localhost on which the admin API is exposed, disabled \
+            unless set"
+  )]
+  admin: Option<u16>,
+
   #[clap(
     long,
     parse(from_os_str),
@@ -51,6 +64,43 @@ pub struct CliOpts {
     default_value = "~/.walletconnect/"
   )]
   data_dir: PathBuf,
+
+  #[clap(
+    long,
+    help = "seconds before an unacknowledged message is delivered again",
+    default_value = "5"
+  )]
+  redelivery_backoff: u64,
+
+  #[clap(
+    long,
+    help = "maximum seconds between redeliveries of an unacknowledged message",
+    default_value = "300"
+  )]
+  redelivery_max_backoff: u64,
+
+  #[clap(
+    long,
+    help = "redeliveries before an unacknowledged message is dead-lettered",
+    default_value = "8"
+  )]
+  redelivery_attempts: u32,
+
+  #[clap(
+    long,
+    help = "number of message gossip shards in the network",
+    default_value = "1"
+  )]
+  shards: u16,
+
+  #[clap(
+    long,
+    help = "message shard this node is a member of, defaults to all shards"
+  )]
+  shard: Vec<u16>,
+
+  #[clap(long, help = "p2p identity of a banned peer to unban on startup")]
+  pub unban: Vec<PeerId>,
 }
 
 impl CliOpts {
@@ -73,23 +123,12 @@ impl CliOpts {
       .collect()
   }
 
-  /// Lists all multiaddresses of known peers.
+  /// Lists all seeds of known peers.
   /// Those peers are used as first bootstrap nodes to join
-  /// the p2p gossip network.
-  pub fn peers(&self) -> Vec<Multiaddr> {
-    self
-      .peer
-      .iter()
-      .map(|addr| {
-        let mut maddr = Multiaddr::empty();
-        maddr.push(match *addr {
-          SocketAddr::V4(addr) => Protocol::Ip4(*addr.ip()),
-          SocketAddr::V6(addr) => Protocol::Ip6(*addr.ip()),
-        });
-        maddr.push(Protocol::Tcp(addr.port()));
-        maddr
-      })
-      .collect()
+  /// the p2p gossip network. Hostnames are resolved on every
+  /// bootstrap attempt.
+  pub fn peers(&self) -> Vec<Seed> {
+    self.peer.clone()
   }
 
   /// Retreives the public key of the current node.
@@ -127,6 +166,52 @@ impl CliOpts {
     })
   }
 
+  /// If an admin port is provided, returns the socketaddr on which the
+  /// admin API is listening. It is bound to localhost only, so the node
+  /// can be managed only by operators on the same machine.
+  pub fn admin_endpoint(&self) -> Option<SocketAddr> {
+    self
+      .admin
+      .map(|port| SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port))
+  }
+
+  /// How unacknowledged messages are retried for subscribers on this node.
+  /// The delay between attempts doubles after every redelivery, up to
+  /// the maximum backoff.
+  pub fn redelivery(&self) -> RedeliveryConfig {
+    RedeliveryConfig {
+      initial_backoff: Duration::from_secs(self.redelivery_backoff),
+      max_backoff: Duration::from_secs(self.redelivery_max_backoff),
+      max_attempts: self.redelivery_attempts,
+    }
+  }
+
+  /// The message gossip shards of the network and the ones this node
+  /// replicates. Nodes are members of all shards unless told otherwise.
+  pub fn shards(&self) -> Result<ShardConfig, std::io::Error> {
+    if self.shards == 0 {
+      return Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        "the network needs at least one shard",
+      ));
+    }
+
+    if let Some(shard) = self.shard.iter().find(|s| **s >= self.shards) {
+      return Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("shard {shard} is out of range of {} shards", self.shards),
+      ));
+    }
+
+    Ok(ShardConfig {
+      count: self.shards,
+      members: match self.shard.is_empty() {
+        true => (0..self.shards).collect(),
+        false => self.shard.clone(),
+      },
+    })
+  }
+
   /// Gets the data directory for the this chain.
   /// The chain directory is <top-level-data-dir>/<chain-id>/*
   pub fn data_dir(&self) -> Result<PathBuf, std::io::Error> {
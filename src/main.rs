@@ -6,8 +6,9 @@ use {
   futures::StreamExt,
   network::{Network, NetworkEvent},
   rpc::RpcService,
+  std::time::Duration,
   storage::PersistentStorage,
-  tracing::{info, Level},
+  tracing::{info, warn, Level},
   tracing_subscriber::{filter::filter_fn, prelude::*},
 };
 
@@ -19,6 +20,10 @@ mod primitives;
 mod rpc;
 mod storage;
 
+/// How long the node keeps running after it has left the network on
+/// shutdown, so that peers get told that it is gone.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
 fn print_essentials(opts: &CliOpts) -> anyhow::Result<()> {
   info!("Starting WalletConnect Inter-Relay Network node");
   info!("Version: {}", env!("CARGO_PKG_VERSION"));
@@ -32,6 +37,9 @@ fn print_essentials(opts: &CliOpts) -> anyhow::Result<()> {
   );
   info!("Bootstrap peers: {:?}", opts.peers());
   info!("RPC Endpoints: {:?}", opts.rpc_endpoints());
+  info!("Admin Endpoint: {:?}", opts.admin_endpoint());
+  info!("Redelivery: {:?}", opts.redelivery());
+  info!("Message shards: {:?}", opts.shards()?);
 
   Ok(())
 }
@@ -60,6 +68,11 @@ async fn main() -> anyhow::Result<()> {
   // Print general startup configuration information.
   print_essentials(&opts)?;
 
+  // the per-node local storage, responsible for
+  // storing data that should survive crashes, such
+  // as the mailbox
+  let storage = PersistentStorage::new(opts.data_dir()?)?;
+
   // Create the P2P networking layer.
   // Networking runs on its own separate thread,
   // and emits events by calling .poll()
@@ -68,26 +81,36 @@ async fn main() -> anyhow::Result<()> {
     opts.secret.clone(),
     opts.listen_multiaddrs().into_iter(), // our adresses
     opts.peers(),                         // bootstrap peers.
+    opts.shards()?,                       // message gossip shards
+    storage.clone(),                      // persisted peer bans
   )
   .await?;
 
+  // bans lifted by the operator
+  for peer in &opts.unban {
+    network.unban_peer(*peer)?;
+  }
+
   // routes messages to topics on the local node
   // if the subscription is managed by this node,
   // otherwise store the message until a new subscription
   // is established for its topic or the message is ACKd by
   // some other node as delivered.
-  let mut bus = MessageBus::new();
-
-  // the per-node local storage, responsible for
-  // storing data that should survive crashes, such
-  // as the mailbox
-  let storage = PersistentStorage::new(opts.data_dir()?)?;
+  let mut bus = MessageBus::new(storage.clone(), opts.redelivery());
 
   // for nodes that expose an external WS rpc service
-  let mut apisvc = opts
-    .rpc_endpoints()
-    .map(|addrs| RpcService::new(addrs, storage, opts.identity()));
+  // or a local admin service for their operators
+  let mut apisvc = match (opts.rpc_endpoints(), opts.admin_endpoint()) {
+    (None, None) => None,
+    (addrs, admin) => Some(RpcService::new(
+      addrs.unwrap_or_default(),
+      admin,
+      storage,
+      opts.identity(),
+    )),
+  };
 
+  let mut shutdown = Box::pin(tokio::signal::ctrl_c());
   loop {
     tokio::select! {
       // core services:
@@ -95,7 +118,14 @@ async fn main() -> anyhow::Result<()> {
       Some(event) = bus.next() => bus::handle!(event, network),
 
       // optional services:
-      Some(event) = apisvc.next() => rpc::handle!(event, bus, network)
+      Some(event) = apisvc.next() => rpc::handle!(event, bus, network),
+
+      _ = &mut shutdown => {
+        info!("Shutting down, leaving the network");
+        network.leave()?;
+        tokio::time::sleep(SHUTDOWN_GRACE_PERIOD).await;
+        return Ok(());
+      }
     }
   }
 }
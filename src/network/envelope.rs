@@ -0,0 +1,114 @@
+use {
+  crate::primitives::{unix_time, Keypair, Pubkey},
+  ed25519_dalek::{PublicKey, Signature, Signer},
+  serde::{Deserialize, Serialize},
+  std::time::Duration,
+  thiserror::Error,
+};
+
+/// How far the timestamp of an envelope may drift from the local clock,
+/// in either direction, before the envelope is rejected.
+const MAX_CLOCK_DRIFT: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Error)]
+pub enum EnvelopeError {
+  #[error("Malformed envelope: {0}")]
+  Malformed(#[from] bincode::Error),
+
+  #[error("Invalid origin node identity {0}")]
+  InvalidOrigin(Pubkey),
+
+  #[error("Invalid signature from {0}")]
+  InvalidSignature(Pubkey),
+
+  #[error("Envelope from {0} is outside of the accepted time window")]
+  Stale(Pubkey),
+}
+
+/// A gossip payload signed by the relay node that originated it.
+///
+/// Everything relay nodes publish on the message, subscriptions and ack
+/// topics is wrapped in an envelope, so receivers can tell which node a
+/// payload came from and that it was not altered or forged on the way by
+/// other peers. The signature also covers a domain, which keeps a payload
+/// signed for one kind of topic from being replayed on another.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+  /// The relay node that created and signed the payload.
+  pub origin: Pubkey,
+
+  /// Milliseconds since unix epoch when the envelope was sealed.
+  pub timestamp: u64,
+
+  pub payload: Vec<u8>,
+
+  signature: Signature,
+}
+
+impl Envelope {
+  /// Wraps a payload and signs it with the identity of this node.
+  pub fn seal(keypair: &Keypair, domain: &str, payload: Vec<u8>) -> Self {
+    let origin = keypair.public();
+    let timestamp = unix_time().as_millis() as u64;
+    let signature =
+      keypair.sign(&signed_bytes(domain, &origin, timestamp, &payload));
+    Self {
+      origin,
+      timestamp,
+      payload,
+      signature,
+    }
+  }
+
+  pub fn from_bytes(bytes: &[u8]) -> Result<Self, EnvelopeError> {
+    Ok(bincode::deserialize(bytes)?)
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    bincode::serialize(self).expect("failed to serialize envelope")
+  }
+
+  /// Checks that the envelope was signed by its origin node for the given
+  /// domain and that it was sealed recently enough.
+  pub fn verify(&self, domain: &str) -> Result<(), EnvelopeError> {
+    let key = PublicKey::from_bytes(self.origin.as_ref())
+      .map_err(|_| EnvelopeError::InvalidOrigin(self.origin))?;
+
+    key
+      .verify_strict(
+        &signed_bytes(domain, &self.origin, self.timestamp, &self.payload),
+        &self.signature,
+      )
+      .map_err(|_| EnvelopeError::InvalidSignature(self.origin))?;
+
+    let now = unix_time().as_millis() as u64;
+    let drift = Duration::from_millis(now.abs_diff(self.timestamp));
+    if drift > MAX_CLOCK_DRIFT {
+      return Err(EnvelopeError::Stale(self.origin));
+    }
+
+    Ok(())
+  }
+
+  /// Parses and verifies an envelope received from the network.
+  pub fn open(bytes: &[u8], domain: &str) -> Result<Self, EnvelopeError> {
+    let envelope = Self::from_bytes(bytes)?;
+    envelope.verify(domain)?;
+    Ok(envelope)
+  }
+}
+
+fn signed_bytes(
+  domain: &str,
+  origin: &Pubkey,
+  timestamp: u64,
+  payload: &[u8],
+) -> Vec<u8> {
+  let mut bytes = Vec::with_capacity(domain.len() + payload.len() + 48);
+  bytes.extend_from_slice(&(domain.len() as u64).to_be_bytes());
+  bytes.extend_from_slice(domain.as_bytes());
+  bytes.extend_from_slice(origin.as_ref());
+  bytes.extend_from_slice(&timestamp.to_be_bytes());
+  bytes.extend_from_slice(payload);
+  bytes
+}
@@ -0,0 +1,151 @@
+use {
+  super::{config::Config, error::RpcError},
+  crate::primitives::unix_time,
+  libp2p::core::PeerId,
+  serde::{Deserialize, Serialize},
+  std::{collections::HashMap, time::Duration},
+};
+
+/// Why a peer was banned.
+///
+/// Reasons differ in severity, a peer that looks like it runs an older
+/// version of the protocol is treated more leniently than one that claims
+/// to be someone else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BanReason {
+  /// The peer used an action that its negotiated protocol version does
+  /// not have, most likely it is running a different release.
+  IncompatibleVersion,
+
+  /// The peer sent a message that does not follow the protocol.
+  ProtocolViolation,
+
+  /// The peer sent a payload that expands beyond the allowed size. Such
+  /// payloads are now dropped without a ban, as size limits may differ
+  /// between nodes, but bans recorded earlier may still carry it.
+  OversizedPayload,
+
+  /// The peer claimed the identity of another peer.
+  Impersonation,
+}
+
+impl BanReason {
+  fn severity(&self) -> u32 {
+    match self {
+      BanReason::IncompatibleVersion => 1,
+      BanReason::ProtocolViolation => 2,
+      BanReason::OversizedPayload => 3,
+      BanReason::Impersonation => 4,
+    }
+  }
+}
+
+impl From<&RpcError> for BanReason {
+  fn from(error: &RpcError) -> Self {
+    match error {
+      RpcError::UnsupportedAction(_) => BanReason::IncompatibleVersion,
+      RpcError::ImpersonatedPeer(..) => BanReason::Impersonation,
+      RpcError::InvalidPeerId | RpcError::MalformedPayload => {
+        BanReason::ProtocolViolation
+      }
+    }
+  }
+}
+
+/// A ban imposed on a peer, along with the offences that led to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BanRecord {
+  /// The most recent offence.
+  pub reason: BanReason,
+
+  /// Accumulated severity of all offences that were not forgiven yet,
+  /// every offence doubles the ban duration for each point of severity.
+  pub score: u32,
+
+  /// Unix timestamp (in seconds) of the most recent offence.
+  pub since: u64,
+
+  /// Unix timestamp (in seconds) when the ban is lifted.
+  pub until: u64,
+}
+
+impl BanRecord {
+  pub fn is_active(&self) -> bool {
+    unix_time().as_secs() < self.until
+  }
+}
+
+/// Peers that are banned from this node.
+///
+/// Bans are lifted after a while, and repeat offenders are banned for
+/// longer each time. Offences are forgiven once a peer has behaved for
+/// as long as the longest possible ban.
+pub struct BanList {
+  duration: Duration,
+  max_duration: Duration,
+  bans: HashMap<PeerId, BanRecord>,
+}
+
+impl BanList {
+  pub fn new(config: &Config) -> Self {
+    Self {
+      duration: config.ban_duration,
+      max_duration: config.max_ban_duration,
+      bans: HashMap::new(),
+    }
+  }
+
+  pub fn is_banned(&self, peer: &PeerId) -> bool {
+    self
+      .bans
+      .get(peer)
+      .map(BanRecord::is_active)
+      .unwrap_or(false)
+  }
+
+  /// Bans a peer for an offence, escalating any previous ban.
+  pub fn ban(&mut self, peer: PeerId, reason: BanReason) -> BanRecord {
+    let now = unix_time().as_secs();
+    let previous = match self.bans.get(&peer) {
+      Some(ban) if !self.forgiven(ban) => ban.score,
+      _ => 0,
+    };
+
+    let score = previous.saturating_add(reason.severity());
+    let factor = 1u32.checked_shl(score - 1).unwrap_or(u32::MAX);
+    let duration = self
+      .duration
+      .checked_mul(factor)
+      .unwrap_or(self.max_duration)
+      .min(self.max_duration);
+
+    let ban = BanRecord {
+      reason,
+      score,
+      since: now,
+      until: now + duration.as_secs(),
+    };
+    self.bans.insert(peer, ban.clone());
+    ban
+  }
+
+  /// Lifts a ban and forgets all previous offences of a peer.
+  pub fn unban(&mut self, peer: &PeerId) -> Option<BanRecord> {
+    self.bans.remove(peer)
+  }
+
+  /// Brings back a ban that was imposed before a restart. Returns false
+  /// if the offences were forgiven in the meantime.
+  pub fn restore(&mut self, peer: PeerId, ban: BanRecord) -> bool {
+    if self.forgiven(&ban) {
+      return false;
+    }
+    self.bans.insert(peer, ban);
+    true
+  }
+
+  fn forgiven(&self, ban: &BanRecord) -> bool {
+    let now = unix_time().as_secs();
+    now >= ban.until.saturating_add(self.max_duration.as_secs())
+  }
+}
@@ -1,9 +1,14 @@
 use {
   super::{
+    bans::{BanList, BanReason, BanRecord},
     config::Config,
+    connection::ProtocolVersion,
+    diversity::{DiversityMetrics, ObservedAddresses},
     error::PublishError,
-    handler::EpisubHandler,
+    handler::{EpisubHandler, EpisubHandlerCommand, EpisubHandlerOutput},
     rpc,
+    schedule::PollSchedule,
+    score::{PeerScore, PeerScores, Penalty},
     topic::TopicMesh,
     view::AddressablePeer,
   },
@@ -19,6 +24,7 @@ use {
     },
     multiaddr::Protocol,
     swarm::{
+      dial_opts::{DialOpts, PeerCondition},
       CloseConnection,
       DialError,
       NetworkBehaviour,
@@ -27,15 +33,16 @@ use {
       PollParameters,
     },
   },
-  rand::Rng,
+  sha3::{Digest, Sha3_256},
   std::{
     collections::{HashMap, HashSet, VecDeque},
     iter,
     net::{Ipv4Addr, Ipv6Addr},
     task::{Context, Poll},
+    time::Instant,
   },
   tracing::{debug, trace, warn},
-  zstd::encode_all,
+  zstd::{decode_all, encode_all},
 };
 
 /// Event that can be emitted by the episub behaviour.
@@ -47,13 +54,22 @@ pub enum EpisubEvent {
     payload: Bytes,
   },
   Subscribed(String),
+  /// A message sent directly to a peer could not reach it.
+  Undelivered {
+    topic: String,
+    peer: PeerId,
+    payload: Bytes,
+  },
   PeerAdded(PeerId),
   PeerRemoved(PeerId),
-  _Unsubscibed(String),
+  /// A peer violated the protocol and is banned until the
+  /// record expires or it is unbanned explicitly.
+  PeerBanned(PeerId, BanRecord),
+  Unsubscribed(String),
 }
 
 pub(crate) type EpisubNetworkBehaviourAction =
-  NetworkBehaviourAction<EpisubEvent, EpisubHandler, rpc::Rpc>;
+  NetworkBehaviourAction<EpisubEvent, EpisubHandler, EpisubHandlerCommand>;
 
 /// Network behaviour that handles the Episub protocol.
 ///
@@ -73,20 +89,41 @@ pub struct Episub {
   /// Per-topic node membership
   topics: HashMap<String, TopicMesh>,
 
-  /// A list of peers that have violated the protocol
-  /// and are pemanently banned from this node. All their
-  /// communication will be ignored and connections rejected.
-  banned_peers: HashSet<PeerId>,
+  /// Order in which topic meshes are polled.
+  poll_schedule: PollSchedule,
+
+  /// Peers that have violated the protocol and are banned
+  /// from this node for a while. All their communication
+  /// will be ignored and connections rejected.
+  banned_peers: BanList,
+
+  /// Track record of all known peers, shared with topic meshes.
+  scores: PeerScores,
+
+  /// When active peers were last probed for round trip time.
+  last_probe: Instant,
 
   /// Topics that we want to join, but haven't found a node
   /// to connect to.
   pending_topics: HashSet<String>,
 
+  /// Peers remembered from previous runs of this node, every
+  /// new topic mesh starts with them in its passive view.
+  known_peers: Vec<AddressablePeer>,
+
   /// events that need to yielded to the outside when polling
   out_events: VecDeque<EpisubNetworkBehaviourAction>,
 
-  /// a mapping of known peerid to the addresses they have dialed us from
-  peer_addresses: HashMap<PeerId, Multiaddr>,
+  /// a mapping of known peerid to the addresses they have dialed us from,
+  /// shared with topic meshes.
+  peer_addresses: ObservedAddresses,
+
+  /// Peers with at least one established connection.
+  connected_peers: HashSet<PeerId>,
+
+  /// Protocol versions negotiated with connected peers. Messages
+  /// using actions a peer does not understand are not sent to it.
+  peer_versions: HashMap<PeerId, ProtocolVersion>,
 
   /// This is the set of peers that we have managed to dial before we started
   /// listening on an external address that is not localhost. It does not make
@@ -100,13 +137,22 @@ pub struct Episub {
 
 impl Episub {
   pub fn new(config: Config) -> Self {
+    let banned_peers = BanList::new(&config);
+    let scores = PeerScores::new(&config);
+    let poll_schedule = PollSchedule::new(config.topic_poll_budget);
     Self {
       config,
       local_node: None,
       topics: HashMap::new(),
-      peer_addresses: HashMap::new(),
-      banned_peers: HashSet::new(),
+      poll_schedule,
+      peer_addresses: ObservedAddresses::default(),
+      connected_peers: HashSet::new(),
+      peer_versions: HashMap::new(),
+      banned_peers,
+      scores,
+      last_probe: Instant::now(),
       pending_topics: HashSet::new(),
+      known_peers: Vec::new(),
       out_events: VecDeque::new(),
       early_peers: HashSet::new(),
     }
@@ -124,12 +170,12 @@ impl Episub {
   ///
   /// if the connection to the peer is dropped or otherwise the peer becomes
   /// unreachable, then this event is silently dropped.
-  fn _send_message(&mut self, peer_id: PeerId, message: rpc::Rpc) {
+  fn send_message(&mut self, peer_id: PeerId, message: rpc::Rpc) {
     self
       .out_events
       .push_back(NetworkBehaviourAction::NotifyHandler {
         peer_id,
-        event: message,
+        event: message.into(),
         handler: NotifyHandler::Any,
       })
   }
@@ -154,16 +200,15 @@ impl Episub {
   /// collection that will send a join request to any node we connect to,
   /// until one of the nodes responds with another NEIGHBOR message.
   pub fn subscribe(&mut self, topic: String) -> bool {
-    if self.topics.get(&topic).is_some() {
+    if self.topics.contains_key(&topic) {
       debug!("Already subscribed to topic {}", topic);
       false
     } else {
       debug!("Subscribing to topic: {}", topic);
       if let Some(ref node) = self.local_node {
-        self.topics.insert(
-          topic.clone(),
-          TopicMesh::new(topic.clone(), self.config.clone(), node.clone()),
-        );
+        let mesh = self.new_mesh(&topic, node.clone());
+        self.topics.insert(topic.clone(), mesh);
+        self.poll_schedule.insert(topic.clone());
         self
           .out_events
           .push_back(EpisubNetworkBehaviourAction::GenerateEvent(
@@ -179,57 +224,277 @@ impl Episub {
   /// Graceful removal from cluster.
   ///
   /// Stops responding to messages sent to this topic and informs
-  /// all peers in the active and passive views that we are withdrawing
-  /// from the cluster.
-  pub fn _unsubscibe(&mut self, topic: String) -> bool {
-    if self.topics.get(&topic).is_none() {
-      warn!(
-        "Attempt to unsubscribe from a non-subscribed topic {}",
-        topic
-      );
-      false
-    } else {
-      debug!("unsubscribing from topic: {}", topic);
-      self.pending_topics.remove(&topic);
-      if let Some(mesh) = self.topics.remove(&topic) {
-        for peer in mesh.nodes().active().map(|ap| ap.peer_id) {
-          trace!("disconnecting from peer {} on topic {}", peer, topic);
-          self._send_message(peer, rpc::Rpc {
-            topic: topic.clone(),
-            action: Some(rpc::rpc::Action::Disconnect(rpc::Disconnect {
-              alive: false, // remove from peers passive view as well
-            })),
+  /// all peers in the active view that we are withdrawing from the
+  /// cluster. Connections to peers that are not active in any of
+  /// the remaining topics are closed once the goodbye is sent.
+  pub fn unsubscribe(&mut self, topic: &str) -> bool {
+    // subscribed before we knew our own identity,
+    // no mesh was created for the topic yet.
+    if self.pending_topics.remove(topic) {
+      debug!("unsubscribing from pending topic: {}", topic);
+      return true;
+    }
+
+    let mesh = match self.topics.remove(topic) {
+      Some(mesh) => mesh,
+      None => {
+        warn!(
+          "Attempt to unsubscribe from a non-subscribed topic {}",
+          topic
+        );
+        return false;
+      }
+    };
+
+    debug!("unsubscribing from topic: {}", topic);
+    self.poll_schedule.remove(topic);
+    for peer in mesh.nodes().active().map(|ap| ap.peer_id) {
+      trace!("disconnecting from peer {} on topic {}", peer, topic);
+      self.send_message(peer, rpc::Rpc {
+        topic: topic.to_owned(),
+        action: Some(rpc::rpc::Action::Disconnect(rpc::Disconnect {
+          alive: false, // remove from peers passive view as well
+        })),
+      });
+
+      if !self.topics.values().any(|m| m.nodes().is_active(&peer)) {
+        trace!("releasing idle connection to peer {}", peer);
+        self
+          .out_events
+          .push_back(NetworkBehaviourAction::NotifyHandler {
+            peer_id: peer,
+            event: EpisubHandlerCommand::Release,
+            handler: NotifyHandler::Any,
           });
-        }
       }
+    }
 
-      self
-        .out_events
-        .push_back(EpisubNetworkBehaviourAction::GenerateEvent(
-          EpisubEvent::_Unsubscibed(topic),
-        ));
+    self
+      .out_events
+      .push_back(EpisubNetworkBehaviourAction::GenerateEvent(
+        EpisubEvent::Unsubscribed(topic.to_owned()),
+      ));
 
-      true
+    true
+  }
+
+  /// Lifts a ban on a peer and forgets its previous offences.
+  pub fn unban_peer(&mut self, peer: &PeerId) -> Option<BanRecord> {
+    let ban = self.banned_peers.unban(peer);
+    if ban.is_some() {
+      debug!("Unbanned peer {}", peer);
     }
+    ban
   }
 
+  /// Brings back a ban imposed before this node was restarted. Returns
+  /// false if the peer has been forgiven in the meantime.
+  pub fn restore_ban(&mut self, peer: PeerId, ban: BanRecord) -> bool {
+    self.banned_peers.restore(peer, ban)
+  }
+
+  /// Track records of all peers this node is connected to
+  /// or that have misbehaved recently.
+  pub fn peer_scores(&self) -> Vec<(PeerId, PeerScore)> {
+    self.scores.all()
+  }
+
+  /// Remembers a peer seen in a previous run of this node. Known peers
+  /// are candidates for the passive views of all topics, so the node
+  /// can rejoin the network even if no bootstrap node is reachable.
+  pub fn add_known_peer(&mut self, peer: PeerId, addresses: Vec<Multiaddr>) {
+    let peer = AddressablePeer {
+      peer_id: peer,
+      addresses: addresses.into_iter().collect(),
+    };
+    for (topic, mesh) in self.topics.iter_mut() {
+      if self.config.authorizer.allow(topic, &peer.peer_id) {
+        mesh.add_known_peers(iter::once(peer.clone()));
+      }
+    }
+    self.known_peers.push(peer);
+  }
+
+  /// Peers in the active view of any topic, along with
+  /// the addresses they advertise.
+  pub fn active_peers(&self) -> Vec<(PeerId, Vec<Multiaddr>)> {
+    let mut peers = HashMap::<PeerId, HashSet<Multiaddr>>::new();
+    for mesh in self.topics.values() {
+      for peer in mesh.nodes().active() {
+        peers
+          .entry(peer.peer_id)
+          .or_default()
+          .extend(peer.addresses.iter().cloned());
+      }
+    }
+    peers
+      .into_iter()
+      .map(|(peer, addresses)| (peer, addresses.into_iter().collect()))
+      .collect()
+  }
+
+  /// True if this node has fewer active peers on a subscribed
+  /// topic than it needs to stay connected to the rest of its mesh.
+  pub fn starved(&self, topic: &str) -> bool {
+    self
+      .topics
+      .get(topic)
+      .map(|mesh| mesh.nodes().starved())
+      .unwrap_or(false)
+  }
+
+  /// True if a connected peer was dialed at or has dialed us from
+  /// the given address.
+  pub fn connected_at(&self, addr: &Multiaddr) -> bool {
+    self.peer_addresses.contains(addr)
+  }
+
+  /// Number of peers kept out of the views of each topic
+  /// to preserve subnet diversity.
+  pub fn diversity_metrics(&self) -> Vec<(String, DiversityMetrics)> {
+    self
+      .topics
+      .iter()
+      .map(|(topic, mesh)| (topic.clone(), mesh.nodes().diversity_metrics()))
+      .collect()
+  }
+
+  /// Broadcasts a message to all members of a topic.
+  ///
+  /// The message id is derived from its contents, so the same message
+  /// published more than once, by this or any other node, is recognized
+  /// as a duplicate and delivered only once.
   pub fn publish(
     &mut self,
     topic: &str,
     message: Vec<u8>,
   ) -> Result<u64, PublishError> {
-    if let Some(topic) = self.topics.get_mut(topic) {
-      let id: u64 = rand::thread_rng().gen();
-      let message = match self.config.enable_compression {
-        true => encode_all(message.as_slice(), self.config.compression_level)?,
-        false => message,
-      };
-      topic.publish(id, message.into());
+    let id = content_id(&message);
+    self.publish_with_id(topic, id, message)
+  }
+
+  /// Broadcasts a message to all members of a topic under an id chosen by
+  /// the caller. Use this when the same logical message may be published
+  /// with different bytes, for example when it is signed by its publisher,
+  /// and duplicates should still be collapsed.
+  pub fn publish_with_id(
+    &mut self,
+    topic: &str,
+    id: u64,
+    message: Vec<u8>,
+  ) -> Result<u64, PublishError> {
+    if self.topics.contains_key(topic) {
+      let message = self.encode_payload(message)?;
+      let topic = self.topics.get_mut(topic).expect("checked above");
+      topic.publish(id, message.into(), self.config.enable_compression);
       Ok(id)
     } else {
       Err(PublishError::TopicNotSubscribed)
     }
   }
+
+  /// Sends a message on a topic straight to one peer, instead of
+  /// broadcasting it to all topic members.
+  ///
+  /// If there is no connection to the peer, a temporary one is dialed on
+  /// the given addresses. When the peer can't be reached, the message is
+  /// returned through [`EpisubEvent::Undelivered`].
+  pub fn send_direct(
+    &mut self,
+    topic: &str,
+    peer: PeerId,
+    addresses: Vec<Multiaddr>,
+    id: u64,
+    message: Vec<u8>,
+  ) -> Result<u64, PublishError> {
+    if !self.topics.contains_key(topic) {
+      return Err(PublishError::TopicNotSubscribed);
+    }
+    self.direct(topic, peer, addresses, id, message, false)
+  }
+
+  /// Publishes a message on a topic that this node is not subscribed to,
+  /// by handing it over to a member of the topic that broadcasts it on
+  /// our behalf. Delivery works the same way as in [`Self::send_direct`].
+  ///
+  /// The member broadcasts the message under the given id, see
+  /// [`Self::publish_with_id`].
+  pub fn forward(
+    &mut self,
+    topic: &str,
+    peer: PeerId,
+    addresses: Vec<Multiaddr>,
+    id: u64,
+    message: Vec<u8>,
+  ) -> Result<u64, PublishError> {
+    self.direct(topic, peer, addresses, id, message, true)
+  }
+
+  fn direct(
+    &mut self,
+    topic: &str,
+    peer: PeerId,
+    addresses: Vec<Multiaddr>,
+    id: u64,
+    message: Vec<u8>,
+    relay: bool,
+  ) -> Result<u64, PublishError> {
+    let message = self.encode_payload(message)?;
+    let rpc = rpc::Rpc {
+      topic: topic.to_owned(),
+      action: Some(rpc::rpc::Action::Direct(rpc::Direct {
+        id,
+        payload: message.into(),
+        relay: Some(relay),
+        compressed: Some(self.config.enable_compression),
+      })),
+    };
+
+    if self.connected_peers.contains(&peer) {
+      match self.peer_versions.get(&peer).copied() {
+        // the peer can't take direct messages, let the
+        // caller deliver it some other way right away.
+        Some(version) if !version.supports(&rpc) => self.undelivered(peer, rpc),
+        _ => self.send_message(peer, rpc),
+      }
+    } else {
+      // the message is sent once the connection is up
+      self
+        .out_events
+        .push_back(EpisubNetworkBehaviourAction::Dial {
+          opts: DialOpts::peer_id(peer)
+            .addresses(addresses)
+            .condition(PeerCondition::Disconnected)
+            .build(),
+          handler: EpisubHandler::new(self.config.max_transmit_size, true)
+            .with_message(rpc),
+        });
+    }
+
+    Ok(id)
+  }
+
+  /// Compresses an outgoing payload if compression is enabled. Payloads
+  /// that exceed the size limits are rejected here, receivers would drop
+  /// them anyway.
+  fn encode_payload(&self, message: Vec<u8>) -> Result<Vec<u8>, PublishError> {
+    let limit = self.config.max_decompressed_size;
+    if message.len() > limit {
+      return Err(PublishError::PayloadTooLarge(message.len(), limit));
+    }
+
+    let message = match self.config.enable_compression {
+      true => encode_all(message.as_slice(), self.config.compression_level)?,
+      false => message,
+    };
+
+    let limit = self.config.max_transmit_size;
+    if message.len() > limit {
+      return Err(PublishError::PayloadTooLarge(message.len(), limit));
+    }
+
+    Ok(message)
+  }
 }
 
 impl NetworkBehaviour for Episub {
@@ -248,7 +513,7 @@ impl NetworkBehaviour for Episub {
     _failed_addresses: Option<&Vec<Multiaddr>>,
     _other_established: usize,
   ) {
-    if self.banned_peers.contains(peer_id) {
+    if self.banned_peers.is_banned(peer_id) {
       self.force_disconnect(*peer_id, *connection);
       debug!(
         "Rejected connection from banned peer {} on endpoint {:?}",
@@ -262,6 +527,9 @@ impl NetworkBehaviour for Episub {
       peer_id, endpoint
     );
 
+    self.connected_peers.insert(*peer_id);
+    self.scores.connected(*peer_id);
+
     // preserve a mapping from peer id to the address that was
     // used to establish the connection.
     self.peer_addresses.insert(*peer_id, match endpoint {
@@ -306,9 +574,13 @@ impl NetworkBehaviour for Episub {
   fn inject_dial_failure(
     &mut self,
     peer_id: Option<PeerId>,
-    _: Self::ConnectionHandler,
+    handler: Self::ConnectionHandler,
     error: &DialError,
   ) {
+    if let Some(peer_id) = peer_id {
+      self.recover_direct_messages(peer_id, handler, error);
+    }
+
     if !matches!(error, DialError::DialPeerConditionFalse(_)) {
       if let Some(peer_id) = peer_id {
         debug!("Dialing peer {} failed: {:?}", peer_id, error);
@@ -326,13 +598,19 @@ impl NetworkBehaviour for Episub {
     _: &ConnectionId,
     endpoint: &ConnectedPoint,
     _: EpisubHandler,
-    _remaining_established: usize,
+    remaining_established: usize,
   ) {
     debug!(
       "Connection to peer {} closed on endpoint {:?}",
       peer_id, endpoint
     );
 
+    if remaining_established == 0 {
+      self.connected_peers.remove(peer_id);
+      self.peer_versions.remove(peer_id);
+      self.scores.disconnected(peer_id);
+    }
+
     for (_, mesh) in self.topics.iter_mut() {
       // remove from active keep in passive
       mesh.disconnected(*peer_id, true);
@@ -346,9 +624,22 @@ impl NetworkBehaviour for Episub {
     &mut self,
     peer_id: PeerId,
     connection: ConnectionId,
-    event: rpc::Rpc,
+    output: EpisubHandlerOutput,
   ) {
-    if self.banned_peers.contains(&peer_id) {
+    let (event, version) = match output {
+      EpisubHandlerOutput::Received(event, version) => (event, version),
+      EpisubHandlerOutput::Negotiated(version) => {
+        debug!("Negotiated protocol {:?} with peer {}", version, peer_id);
+        self.peer_versions.insert(peer_id, version);
+        return;
+      }
+      EpisubHandlerOutput::Unsupported(message) => {
+        self.undelivered(peer_id, message);
+        return;
+      }
+    };
+
+    if self.banned_peers.is_banned(&peer_id) {
       debug!(
         "rejecting event from a banned peer {}: {:?}",
         peer_id, event
@@ -374,7 +665,9 @@ impl NetworkBehaviour for Episub {
     );
 
     if event.action.is_none() {
-      self.ban_peer(peer_id, connection); // peer is violating the protocol
+      // peer is violating the protocol
+      self.scores.penalize(peer_id, Penalty::InvalidMessage);
+      self.ban_peer(peer_id, connection, BanReason::ProtocolViolation);
       return;
     }
 
@@ -383,9 +676,10 @@ impl NetworkBehaviour for Episub {
       // a syntax error or is unparsable at the protocol level, then ban
       // the sender from this node. This might indicate a malicious node
       // or an incompatible version of the protocol.
-      if let Err(error) = mesh.inject_rpc_call(peer_id, event) {
+      if let Err(error) = mesh.inject_rpc_call(peer_id, version, event) {
         warn!("Protocol violation: {}", error);
-        self.ban_peer(peer_id, connection);
+        self.scores.penalize(peer_id, Penalty::InvalidMessage);
+        self.ban_peer(peer_id, connection, (&error).into());
       }
     } else {
       // reject any messages on a topic that we're not subscribed to
@@ -403,7 +697,8 @@ impl NetworkBehaviour for Episub {
             action: Some(rpc::rpc::Action::Disconnect(rpc::Disconnect {
               alive: false,
             })),
-          },
+          }
+          .into(),
         });
       self.force_disconnect(peer_id, connection);
     }
@@ -417,25 +712,94 @@ impl NetworkBehaviour for Episub {
     // update local peer identity and addresses
     self.update_local_node_info(params);
 
+    // periodically measure how far active peers are from us
+    if self.last_probe.elapsed() > self.config.probe_interval {
+      self.probe_active_peers();
+      self.last_probe = Instant::now();
+    }
+
     // bubble up any outstanding behaviour-level events in fifo order
     if let Some(event) = self.out_events.pop_front() {
       return Poll::Ready(event);
     }
 
     // next bubble up events for all topics
-    // todo, randomize polling among topics, otherwise
-    // some topics might be starved by more active ones
-    for mesh in self.topics.values_mut() {
-      if let Poll::Ready(event) = mesh.poll_unpin(cx) {
-        return Poll::Ready(event);
-      }
-    }
-
-    Poll::Pending
+    self.poll_topics(cx)
   }
 }
 
 impl Episub {
+  /// Polls topic meshes in round-robin order.
+  ///
+  /// Each topic yields at most `topic_poll_budget` events in a row before
+  /// the next topic gets its turn, so a busy topic can't starve the others.
+  fn poll_topics(
+    &mut self,
+    cx: &mut Context<'_>,
+  ) -> Poll<EpisubNetworkBehaviourAction> {
+    let topics = &mut self.topics;
+    self.poll_schedule.poll(cx, |topic, cx| {
+      topics
+        .get_mut(topic)
+        .expect("polled topics are subscribed")
+        .poll_unpin(cx)
+    })
+  }
+
+  fn new_mesh(&self, topic: &str, local: AddressablePeer) -> TopicMesh {
+    let mut mesh = TopicMesh::new(
+      topic.to_owned(),
+      self.config.clone(),
+      local,
+      self.scores.clone(),
+      self.peer_addresses.clone(),
+    );
+    mesh.add_known_peers(
+      self
+        .known_peers
+        .iter()
+        .filter(|p| self.config.authorizer.allow(topic, &p.peer_id))
+        .cloned(),
+    );
+    mesh
+  }
+
+  /// Sends a latency probe to every peer that is active in any topic.
+  /// Peers active in many topics are probed once, on the first topic.
+  /// Peers on protocol versions without latency probes are skipped.
+  fn probe_active_peers(&mut self) {
+    let mut probed = HashSet::new();
+    for (topic, mesh) in &self.topics {
+      for peer in mesh.nodes().active() {
+        if !probed.insert(peer.peer_id) {
+          continue;
+        }
+        let probes = self
+          .peer_versions
+          .get(&peer.peer_id)
+          .map(|v| *v >= ProtocolVersion::V1_2)
+          .unwrap_or(false);
+        if !probes {
+          continue;
+        }
+        if let Some(nonce) = self.scores.start_probe(&peer.peer_id) {
+          trace!("probing latency of peer {}", peer.peer_id);
+          self
+            .out_events
+            .push_back(NetworkBehaviourAction::NotifyHandler {
+              peer_id: peer.peer_id,
+              handler: NotifyHandler::Any,
+              event: rpc::Rpc {
+                topic: topic.clone(),
+                action: Some(rpc::rpc::Action::Ping(rpc::Ping { nonce })),
+              }
+              .into(),
+            });
+        }
+      }
+    }
+  }
+
   /// updates our own information abount ourselves.
   /// This includes our own peer id, the addresses we are listening on
   /// and any external addresses we are aware of. Excludes any localhost
@@ -455,19 +819,16 @@ impl Episub {
           addresses,
         });
 
-        for topic in self.pending_topics.drain() {
+        let pending: Vec<_> = self.pending_topics.drain().collect();
+        for topic in pending {
           // for any subscripts requested before we
           // we knew about our own peer identity and
           // addresses
           if !self.topics.contains_key(&topic) {
-            self.topics.insert(
-              topic.clone(),
-              TopicMesh::new(
-                topic.clone(),
-                self.config.clone(),
-                self.local_node.as_ref().unwrap().clone(),
-              ),
-            );
+            let local = self.local_node.as_ref().unwrap().clone();
+            let mesh = self.new_mesh(&topic, local);
+            self.topics.insert(topic.clone(), mesh);
+            self.poll_schedule.insert(topic);
           }
         }
 
@@ -489,11 +850,76 @@ impl Episub {
     }
   }
 
-  fn ban_peer(&mut self, peer: PeerId, connection: ConnectionId) {
-    warn!("Banning peer {}", peer);
+  /// Direct messages scheduled on a handler whose dial failed are either
+  /// resent over an existing connection, if the dial was skipped because
+  /// the peer got connected in the meantime, or reported as undelivered.
+  fn recover_direct_messages(
+    &mut self,
+    peer_id: PeerId,
+    handler: EpisubHandler,
+    error: &DialError,
+  ) {
+    for message in handler.into_pending() {
+      if let DialError::DialPeerConditionFalse(_) = error {
+        self.send_message(peer_id, message);
+      } else {
+        self.undelivered(peer_id, message);
+      }
+    }
+  }
+
+  /// Reports a direct message that could not be sent to a peer through
+  /// [`EpisubEvent::Undelivered`]. Other messages that could not be sent
+  /// are dropped, they are part of protocols that recover on their own.
+  fn undelivered(&mut self, peer_id: PeerId, message: rpc::Rpc) {
+    let (payload, compressed) = match message.action {
+      Some(rpc::rpc::Action::Direct(rpc::Direct {
+        payload,
+        compressed,
+        ..
+      })) => (payload, compressed),
+      action => {
+        trace!("Dropping message {:?} not sent to {}", action, peer_id);
+        return;
+      }
+    };
+
+    let payload = match compressed.unwrap_or(self.config.enable_compression) {
+      true => match decode_all(payload.as_ref()) {
+        Ok(payload) => payload.into(),
+        Err(e) => {
+          warn!("Failed to decompress undelivered message: {}", e);
+          return;
+        }
+      },
+      false => payload,
+    };
+    self
+      .out_events
+      .push_back(EpisubNetworkBehaviourAction::GenerateEvent(
+        EpisubEvent::Undelivered {
+          topic: message.topic,
+          peer: peer_id,
+          payload,
+        },
+      ));
+  }
+
+  fn ban_peer(
+    &mut self,
+    peer: PeerId,
+    connection: ConnectionId,
+    reason: BanReason,
+  ) {
+    let ban = self.banned_peers.ban(peer, reason);
+    warn!("Banning peer {} for {:?}: {:?}", peer, reason, ban);
     self.peer_addresses.remove(&peer);
-    self.banned_peers.insert(peer);
     self.force_disconnect(peer, connection);
+    self
+      .out_events
+      .push_back(EpisubNetworkBehaviourAction::GenerateEvent(
+        EpisubEvent::PeerBanned(peer, ban),
+      ));
   }
 
   fn force_disconnect(&mut self, peer: PeerId, connection: ConnectionId) {
@@ -519,19 +945,23 @@ impl Episub {
       .for_each(|(_, v)| {
         v.initiate_join(AddressablePeer {
           peer_id: peer,
-          addresses: iter::once(self.peer_addresses.get(&peer).unwrap())
-            .cloned()
-            .collect(),
+          addresses: self.peer_addresses.get(&peer).into_iter().collect(),
         })
       });
   }
 }
 
+/// Message id derived from the uncompressed message contents.
+fn content_id(message: &[u8]) -> u64 {
+  let digest = Sha3_256::digest(message);
+  u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
 /// This handles the case when the swarm api starts listening on
 /// 0.0.0.0 and one of the addresses is localhost. Localhost is
 /// meaningless when advertised to remote nodes, so its omitted
 /// when counting local addresses
-fn is_local_address(addr: &Multiaddr) -> bool {
+pub(crate) fn is_local_address(addr: &Multiaddr) -> bool {
   addr.iter().any(|p| {
     // fileter out all localhost addresses
     if let Protocol::Ip4(addr) = p {
@@ -47,7 +47,7 @@ impl<T> Eq for Timed<T> {}
 
 impl<T> PartialOrd for Timed<T> {
   fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-    self.time().partial_cmp(other.time())
+    Some(self.cmp(other))
   }
 }
 
@@ -127,11 +127,12 @@ pub struct MessageRecord {
   pub hop: u32,
   pub sender: PeerId,
   pub payload: Bytes,
+  pub compressed: bool,
 }
 
 impl PartialOrd for MessageRecord {
   fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-    self.hop.partial_cmp(&other.hop)
+    Some(self.cmp(other))
   }
 }
 impl Ord for MessageRecord {
@@ -149,7 +150,7 @@ pub struct MessageInfo {
 
 impl PartialOrd for MessageInfo {
   fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-    self.hop.partial_cmp(&other.hop)
+    Some(self.cmp(other))
   }
 }
 impl Ord for MessageInfo {
@@ -194,6 +195,7 @@ impl From<MessageRecord> for rpc::Message {
       id: record.id,
       hop: record.hop,
       payload: record.payload,
+      compressed: Some(record.compressed),
     }
   }
 }
@@ -3,8 +3,10 @@ use {
   std::{sync::Arc, time::Duration},
 };
 
+type AuthorizerPredicate = dyn Fn(&str, &PeerId) -> bool + Send + Sync;
+
 #[derive(Clone)]
-pub struct PeerAuthorizer(Arc<dyn Fn(&str, &PeerId) -> bool + Send + Sync>);
+pub struct PeerAuthorizer(Arc<AuthorizerPredicate>);
 
 impl PeerAuthorizer {
   pub fn new<F>(predicate: F) -> Self
@@ -39,6 +41,12 @@ pub struct Config {
   /// active view size = C * Ln(N)
   pub passive_view_factor: usize,
 
+  /// Largest share of the active and passive views that peers from one
+  /// subnet (IPv4 /24 or IPv6 /48) may take. Keeps an attacker with many
+  /// peer identities behind a single network from taking over the views.
+  /// Valid values are 0.0 - 1.0, where 1.0 disables the limit.
+  pub max_subnet_share: f64,
+
   /// Maximum size of a message, this applies to
   /// control and payload messages
   pub max_transmit_size: usize,
@@ -84,12 +92,47 @@ pub struct Config {
   /// eager node
   pub hop_optimization_factor: u32,
 
+  /// How often the round trip time to active peers is measured.
+  pub probe_interval: Duration,
+
+  /// The difference in round trip time between a lazy peer that sent
+  /// an IHAVE and the eager peer that delivered the message, that
+  /// triggers grafting the closer lazy peer into the tree.
+  pub rtt_optimization_threshold: Duration,
+
+  /// Maximum number of events one topic can yield in a row when the
+  /// behaviour is polled, before other topics get their turn. Keeps
+  /// control topics responsive while a busy topic is flooded.
+  pub topic_poll_budget: usize,
+
+  /// How long a peer is banned for its first minor protocol violation.
+  /// The duration doubles with every point of severity of subsequent
+  /// violations.
+  pub ban_duration: Duration,
+
+  /// Upper bound on the duration of a single ban. Offences are forgiven
+  /// once a peer goes this long without a violation after its last ban.
+  pub max_ban_duration: Duration,
+
+  /// Time it takes for penalties in a peer score to drop by half.
+  pub score_half_life: Duration,
+
+  /// Peers scoring below this are not used as eager push peers in the
+  /// broadcast tree, they only get IHAVEs unless they are the best peer
+  /// available.
+  pub eager_score_threshold: f64,
+
   /// Defines if message payloads are going to be compressed over the wire.
-  /// This trades processing speed vs network bandwidth. Both sides of the
-  /// p2p protocol must use the same configuration, otherwise they will
-  /// attempt to decompress uncompressed data.
+  /// This trades processing speed vs network bandwidth. Every message is
+  /// flagged as compressed or not, so peers may use different settings
+  /// and this can be changed one node at a time.
   pub enable_compression: bool,
 
+  /// Maximum size of a message payload after decompression. Compressed
+  /// payloads that would expand beyond this size are rejected as a
+  /// protocol violation instead of being inflated in memory.
+  pub max_decompressed_size: usize,
+
   /// A value between 1 and 21. Value of 0 uses zstd default compression
   /// factor. See https://github.com/facebook/zstd for more info.
   pub compression_level: i32,
@@ -134,6 +177,21 @@ impl Config {
     self.max_active_view_size() * self.passive_view_factor
   }
 
+  /// Maximum number of peers from one subnet in the active view.
+  ///
+  /// Never below `min_active_view_size`, so nodes in deployments that
+  /// run in a single subnet can still leave the starving state.
+  pub fn max_active_peers_per_subnet(&self) -> usize {
+    let share = self.max_active_view_size() as f64 * self.max_subnet_share;
+    (share.ceil() as usize).max(self.min_active_view_size())
+  }
+
+  /// Maximum number of peers from one subnet in the passive view.
+  pub fn max_passive_peers_per_subnet(&self) -> usize {
+    let share = self.max_passive_view_size() as f64 * self.max_subnet_share;
+    (share.ceil() as usize).max(1)
+  }
+
   pub fn active_walk_length(&self) -> usize {
     ((self.network_size as f64).log2() as usize).clamp(2, 6)
   }
@@ -151,16 +209,25 @@ impl Default for Config {
       network_size: 1000,
       active_view_factor: 1,
       passive_view_factor: 6,
+      max_subnet_share: 0.5,
       enable_compression: true,
       compression_level: 0,
-      shuffle_probability: 1.0,     // always shuffle
-      max_transmit_size: 1_024_000, // 1 MB
+      shuffle_probability: 1.0,         // always shuffle
+      max_transmit_size: 1_024_000,     // 1 MB
+      max_decompressed_size: 4_096_000, // 4 MB
       shuffle_interval: Duration::from_secs(60),
       lazy_push_window: Duration::from_secs(2),
       history_window: Duration::from_secs(30),
       tick_frequency: Duration::from_millis(200),
       hop_optimization_factor: 4,
+      probe_interval: Duration::from_secs(10),
+      rtt_optimization_threshold: Duration::from_millis(50),
       optimize_sender_tree: true,
+      topic_poll_budget: 16,
+      ban_duration: Duration::from_secs(10 * 60),
+      max_ban_duration: Duration::from_secs(7 * 24 * 60 * 60),
+      score_half_life: Duration::from_secs(10 * 60),
+      eager_score_threshold: -5.0,
       authorizer: PeerAuthorizer::new(|_: &str, _: &PeerId| {
         true // allow all by default
       }),
@@ -1,12 +1,65 @@
 use {
-  super::{codec::EpisubCodec, error::EpisubHandlerError},
+  super::{codec::EpisubCodec, error::EpisubHandlerError, rpc},
   asynchronous_codec::Framed,
   futures::{future, AsyncRead, AsyncWrite},
-  libp2p::core::{InboundUpgrade, OutboundUpgrade, UpgradeInfo},
-  std::{future::Future, iter, pin::Pin},
+  libp2p::core::{
+    upgrade::ProtocolName,
+    InboundUpgrade,
+    OutboundUpgrade,
+    UpgradeInfo,
+  },
+  std::{future::Future, pin::Pin},
   unsigned_varint::codec,
 };
 
+/// Versions of the Episub wire protocol.
+///
+/// All supported versions are advertised when a substream is negotiated,
+/// newest first, so two peers settle on the highest version they have in
+/// common. RPC actions introduced in later versions are never sent to peers
+/// that negotiated an older one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ProtocolVersion {
+  /// HyParView and PlumTree actions.
+  V1_0,
+
+  /// Adds direct messages and compression flags on payloads.
+  V1_1,
+
+  /// Adds latency probes.
+  V1_2,
+}
+
+impl ProtocolVersion {
+  /// All versions this node speaks, in order of preference.
+  pub const SUPPORTED: [ProtocolVersion; 3] = [
+    ProtocolVersion::V1_2,
+    ProtocolVersion::V1_1,
+    ProtocolVersion::V1_0,
+  ];
+
+  /// Tells whether a peer on this version understands an RPC message.
+  pub fn supports(&self, message: &rpc::Rpc) -> bool {
+    match message.action {
+      Some(rpc::rpc::Action::Direct(_)) => *self >= ProtocolVersion::V1_1,
+      Some(rpc::rpc::Action::Ping(_)) | Some(rpc::rpc::Action::Pong(_)) => {
+        *self >= ProtocolVersion::V1_2
+      }
+      _ => true,
+    }
+  }
+}
+
+impl ProtocolName for ProtocolVersion {
+  fn protocol_name(&self) -> &[u8] {
+    match self {
+      ProtocolVersion::V1_0 => b"/episub/1.0.0",
+      ProtocolVersion::V1_1 => b"/episub/1.1.0",
+      ProtocolVersion::V1_2 => b"/episub/1.2.0",
+    }
+  }
+}
+
 #[derive(Debug, Clone)]
 pub struct EpisubConnection {
   max_transmit_size: usize,
@@ -19,11 +72,11 @@ impl EpisubConnection {
 }
 
 impl UpgradeInfo for EpisubConnection {
-  type Info = &'static [u8];
-  type InfoIter = iter::Once<Self::Info>;
+  type Info = ProtocolVersion;
+  type InfoIter = std::array::IntoIter<Self::Info, 3>;
 
   fn protocol_info(&self) -> Self::InfoIter {
-    iter::once(b"/episub/1.0.0")
+    ProtocolVersion::SUPPORTED.into_iter()
   }
 }
 
@@ -35,14 +88,18 @@ where
   #[allow(clippy::type_complexity)] // oh well
   type Future =
     Pin<Box<dyn Future<Output = Result<Self::Output, Self::Error>> + Send>>;
-  type Output = Framed<TSocket, EpisubCodec>;
+  type Output = (Framed<TSocket, EpisubCodec>, ProtocolVersion);
 
-  fn upgrade_inbound(self, socket: TSocket, _: Self::Info) -> Self::Future {
+  fn upgrade_inbound(
+    self,
+    socket: TSocket,
+    version: Self::Info,
+  ) -> Self::Future {
     let mut length_codec = codec::UviBytes::default();
     length_codec.set_max_len(self.max_transmit_size);
-    Box::pin(future::ok(Framed::new(
-      socket,
-      EpisubCodec::new(length_codec),
+    Box::pin(future::ok((
+      Framed::new(socket, EpisubCodec::new(length_codec)),
+      version,
     )))
   }
 }
@@ -55,14 +112,18 @@ where
   #[allow(clippy::type_complexity)] // oh well
   type Future =
     Pin<Box<dyn Future<Output = Result<Self::Output, Self::Error>> + Send>>;
-  type Output = Framed<TSocket, EpisubCodec>;
+  type Output = (Framed<TSocket, EpisubCodec>, ProtocolVersion);
 
-  fn upgrade_outbound(self, socket: TSocket, _: Self::Info) -> Self::Future {
+  fn upgrade_outbound(
+    self,
+    socket: TSocket,
+    version: Self::Info,
+  ) -> Self::Future {
     let mut length_codec = codec::UviBytes::default();
     length_codec.set_max_len(self.max_transmit_size);
-    Box::pin(future::ok(Framed::new(
-      socket,
-      EpisubCodec::new(length_codec),
+    Box::pin(future::ok((
+      Framed::new(socket, EpisubCodec::new(length_codec)),
+      version,
     )))
   }
 }
@@ -0,0 +1,133 @@
+use {
+  super::view::AddressablePeer,
+  libp2p::{core::PeerId, multiaddr::Protocol, Multiaddr},
+  std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex},
+  },
+};
+
+/// Network that a peer is reachable in.
+///
+/// Peers in the same subnet are likely run by the same operator, so
+/// the number of peers from one subnet in a view is limited. Otherwise
+/// an attacker with many peer identities behind a single network could
+/// take over all views of a node and isolate it from the honest part
+/// of the network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Subnet {
+  /// IPv4 /24 prefix.
+  V4([u8; 3]),
+
+  /// IPv6 /48 prefix.
+  V6([u16; 3]),
+
+  /// Peers that don't advertise any IP address share one bucket.
+  Unknown,
+}
+
+impl Subnet {
+  /// All subnets a peer is reachable in.
+  ///
+  /// Peers we are or were connected to are placed in the subnet of the
+  /// address the connection was made on, addresses a peer advertises
+  /// about itself are used only for peers we never connected to.
+  /// Otherwise an attacker could dodge the limits by advertising
+  /// addresses in other subnets.
+  pub fn of(
+    peer: &AddressablePeer,
+    observed: &ObservedAddresses,
+  ) -> HashSet<Subnet> {
+    let subnets: HashSet<_> = match observed.get(&peer.peer_id) {
+      Some(addr) => Subnet::of_address(&addr).into_iter().collect(),
+      None => peer.addresses.iter().filter_map(Subnet::of_address).collect(),
+    };
+
+    match subnets.is_empty() {
+      true => [Subnet::Unknown].into_iter().collect(),
+      false => subnets,
+    }
+  }
+
+  fn of_address(addr: &Multiaddr) -> Option<Subnet> {
+    addr.iter().find_map(|p| match p {
+      Protocol::Ip4(ip) => {
+        let [a, b, c, _] = ip.octets();
+        Some(Subnet::V4([a, b, c]))
+      }
+      Protocol::Ip6(ip) => {
+        let [a, b, c, ..] = ip.segments();
+        Some(Subnet::V6([a, b, c]))
+      }
+      _ => None,
+    })
+  }
+
+  /// Returns the subnet that would go over the limit of peers if the
+  /// given peer was added to a view, if any.
+  pub fn crowded<'a>(
+    view: impl Iterator<Item = &'a AddressablePeer>,
+    peer: &AddressablePeer,
+    limit: usize,
+    observed: &ObservedAddresses,
+  ) -> Option<Subnet> {
+    let subnets = Subnet::of(peer, observed);
+    let members: Vec<_> = view
+      .filter(|member| member.peer_id != peer.peer_id)
+      .map(|member| Subnet::of(member, observed))
+      .collect();
+
+    subnets.into_iter().find(|subnet| {
+      members.iter().filter(|m| m.contains(subnet)).count() >= limit
+    })
+  }
+}
+
+impl fmt::Display for Subnet {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Subnet::V4([a, b, c]) => write!(f, "{}/24", Ipv4Addr::new(*a, *b, *c, 0)),
+      Subnet::V6([a, b, c]) => {
+        write!(f, "{}/48", Ipv6Addr::new(*a, *b, *c, 0, 0, 0, 0, 0))
+      }
+      Subnet::Unknown => write!(f, "unknown"),
+    }
+  }
+}
+
+/// Number of peers kept out of the views of a topic because
+/// their subnet already had as many peers as allowed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiversityMetrics {
+  pub active_rejections: u64,
+  pub passive_rejections: u64,
+}
+
+/// Addresses that connections with peers were established on, either
+/// dialed by us or observed when a peer dialed us.
+///
+/// Clones share the same addresses, the behaviour records them and
+/// each topic mesh holds a clone.
+#[derive(Debug, Clone, Default)]
+pub struct ObservedAddresses(Arc<Mutex<HashMap<PeerId, Multiaddr>>>);
+
+impl ObservedAddresses {
+  pub fn insert(&self, peer: PeerId, addr: Multiaddr) {
+    self.0.lock().unwrap().insert(peer, addr);
+  }
+
+  pub fn remove(&self, peer: &PeerId) {
+    self.0.lock().unwrap().remove(peer);
+  }
+
+  pub fn get(&self, peer: &PeerId) -> Option<Multiaddr> {
+    self.0.lock().unwrap().get(peer).cloned()
+  }
+
+  /// True if any peer was connected on the given address.
+  pub fn contains(&self, addr: &Multiaddr) -> bool {
+    self.0.lock().unwrap().values().any(|a| a == addr)
+  }
+}
@@ -1,4 +1,5 @@
 use {
+  super::connection::ProtocolVersion,
   libp2p::{core::PeerId, multiaddr},
   thiserror::Error,
 };
@@ -19,6 +20,9 @@ pub enum PublishError {
   #[error("Attempt to send a message on an unsubscribed topic")]
   TopicNotSubscribed,
 
+  #[error("Message of {0} bytes exceeds the size limit of {1} bytes")]
+  PayloadTooLarge(usize, usize),
+
   #[error("IO Error: {0}")]
   Io(#[from] std::io::Error),
 }
@@ -31,6 +35,12 @@ pub enum RpcError {
 
   #[error("Peer {0} is impersonating {1}")]
   ImpersonatedPeer(PeerId, PeerId),
+
+  #[error("Message payload is malformed")]
+  MalformedPayload,
+
+  #[error("Action is not supported in protocol version {0:?}")]
+  UnsupportedAction(ProtocolVersion),
 }
 
 /// Errors associated with converting values from
@@ -1,7 +1,7 @@
 use {
   super::{
     codec::EpisubCodec,
-    connection::EpisubConnection,
+    connection::{EpisubConnection, ProtocolVersion},
     error::EpisubHandlerError,
     rpc,
   },
@@ -23,10 +23,48 @@ use {
     io,
     pin::Pin,
     task::{Context, Poll},
+    time::{Duration, Instant},
   },
   tracing::{error, warn},
 };
 
+/// How long a connection that is no longer needed stays open after its
+/// last scheduled message was flushed, so the message makes it to the wire.
+const LINGER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Instructions the behaviour sends to a connection handler.
+#[derive(Debug)]
+pub enum EpisubHandlerCommand {
+  /// Sends an rpc message to the peer.
+  Send(rpc::Rpc),
+
+  /// None of the topics needs this connection anymore, it is closed
+  /// once all messages scheduled on it are sent.
+  Release,
+}
+
+impl From<rpc::Rpc> for EpisubHandlerCommand {
+  fn from(message: rpc::Rpc) -> Self {
+    EpisubHandlerCommand::Send(message)
+  }
+}
+
+/// Events a connection handler reports to the behaviour.
+#[derive(Debug)]
+pub enum EpisubHandlerOutput {
+  /// The protocol version the peer understands was negotiated
+  /// on the outbound substream.
+  Negotiated(ProtocolVersion),
+
+  /// An rpc message was received from the peer on the
+  /// protocol version negotiated on the inbound substream.
+  Received(rpc::Rpc, ProtocolVersion),
+
+  /// A scheduled message uses an action the peer does not understand
+  /// and was not sent.
+  Unsupported(rpc::Rpc),
+}
+
 /// State of the inbound substream, opened either by us or by the remote.
 enum InboundSubstreamState {
   /// Waiting for a message from the remote. The idle state for an inbound
@@ -63,10 +101,21 @@ pub struct EpisubHandler {
   outbound_substream: Option<OutboundSubstreamState>,
   /// The single long-lived inbound substream.
   inbound_substream: Option<InboundSubstreamState>,
+  /// Protocol version negotiated on the inbound substream, it is reported
+  /// along with every message received from the peer.
+  inbound_version: Option<ProtocolVersion>,
+  /// Protocol version negotiated on the outbound substream, messages that
+  /// the peer does not understand are not sent.
+  outbound_version: Option<ProtocolVersion>,
+  /// Whether the behaviour was told about the outbound version yet.
+  version_reported: bool,
   /// Whether we want the peer to have strong live connection to us.
   /// This changes when a peer is moved from the active view to the passive
   /// view.
   keep_alive: KeepAlive,
+  /// Temporary connections are dialed only to deliver a shuffle reply or
+  /// a direct message and are never used for anything else.
+  temporary: bool,
   /// The list of messages scheduled to be sent to this peer
   outbound_queue: VecDeque<rpc::Rpc>,
 }
@@ -90,19 +139,42 @@ impl EpisubHandler {
         false => KeepAlive::Yes,
         true => KeepAlive::No,
       },
+      temporary,
       outbound_substream: None,
       inbound_substream: None,
+      inbound_version: None,
+      outbound_version: None,
+      version_reported: false,
       outbound_queue: VecDeque::new(),
     }
   }
+
+  /// Schedules a message to be sent as soon as the connection is
+  /// established. Used when dialing a peer that we want to reach
+  /// directly, but are not connected to yet.
+  ///
+  /// The connection lingers for a while after the message is flushed
+  /// to the substream, so it has a chance to make it to the wire.
+  pub fn with_message(mut self, message: rpc::Rpc) -> Self {
+    self.outbound_queue.push_back(message);
+    if self.keep_alive == KeepAlive::No {
+      self.keep_alive = KeepAlive::Until(Instant::now() + LINGER_TIMEOUT);
+    }
+    self
+  }
+
+  /// Messages that were scheduled on this handler but never sent.
+  pub fn into_pending(self) -> VecDeque<rpc::Rpc> {
+    self.outbound_queue
+  }
 }
 
 impl ConnectionHandler for EpisubHandler {
   type Error = EpisubHandlerError;
-  type InEvent = rpc::Rpc;
+  type InEvent = EpisubHandlerCommand;
   type InboundOpenInfo = ();
   type InboundProtocol = EpisubConnection;
-  type OutEvent = rpc::Rpc;
+  type OutEvent = EpisubHandlerOutput;
   type OutboundOpenInfo = ();
   type OutboundProtocol = EpisubConnection;
 
@@ -114,38 +186,57 @@ impl ConnectionHandler for EpisubHandler {
 
   fn inject_fully_negotiated_inbound(
     &mut self,
-    substream: <Self::InboundProtocol as InboundUpgrade<NegotiatedSubstream>>::Output,
+    (substream, version): <Self::InboundProtocol as InboundUpgrade<
+      NegotiatedSubstream,
+    >>::Output,
     _: Self::InboundOpenInfo,
   ) {
+    self.inbound_version = Some(version);
     self.inbound_substream =
       Some(InboundSubstreamState::WaitingInput(substream))
   }
 
   fn inject_fully_negotiated_outbound(
     &mut self,
-    substream: <Self::OutboundProtocol as OutboundUpgrade<
+    (substream, version): <Self::OutboundProtocol as OutboundUpgrade<
       NegotiatedSubstream,
     >>::Output,
     _: Self::OutboundOpenInfo,
   ) {
+    self.outbound_version = Some(version);
+    self.version_reported = false;
     self.outbound_substream =
       Some(OutboundSubstreamState::WaitingOutput(substream));
   }
 
   fn inject_event(&mut self, event: Self::InEvent) {
-    if self.keep_alive != KeepAlive::Yes {
+    let event = match event {
+      EpisubHandlerCommand::Send(event) => event,
+      EpisubHandlerCommand::Release => {
+        if self.keep_alive == KeepAlive::Yes {
+          self.keep_alive = KeepAlive::Until(Instant::now() + LINGER_TIMEOUT);
+        }
+        return;
+      }
+    };
+
+    let transient = matches!(
+      event.action,
+      Some(rpc::rpc::Action::ShuffleReply(_) | rpc::rpc::Action::Direct(_))
+    );
+
+    if self.temporary {
       // temporary connection are only for
-      // shuffle replies. Don't permit any
-      // outgoing message other than shuffle
-      // reply
-      if let rpc::Rpc {
-        action: Some(rpc::rpc::Action::ShuffleReply(_)),
-        ..
-      } = event
-      {
+      // shuffle replies and direct messages.
+      // Don't permit any other outgoing message.
+      if transient {
         self.outbound_queue.push_back(event);
       }
     } else {
+      if !transient {
+        // a released connection is needed again by some topic
+        self.keep_alive = KeepAlive::Yes;
+      }
       self.outbound_queue.push_back(event);
     }
   }
@@ -161,10 +252,33 @@ impl ConnectionHandler for EpisubHandler {
   }
 
   fn connection_keep_alive(&self) -> KeepAlive {
-    self.keep_alive
+    // temporary and released connections stay open
+    // until all their scheduled messages are sent.
+    let sending = matches!(
+      self.outbound_substream,
+      Some(
+        OutboundSubstreamState::PendingSend(..)
+          | OutboundSubstreamState::PendingFlush(..)
+      )
+    );
+    match self.outbound_queue.is_empty() && !sending {
+      true => self.keep_alive,
+      false => KeepAlive::Yes,
+    }
   }
 
   fn poll(&mut self, cx: &mut Context<'_>) -> Poll<EpisubHandlerEvent> {
+    // let the behaviour know what the peer understands before
+    // it schedules more messages on this connection
+    if let Some(version) = self.outbound_version {
+      if !self.version_reported {
+        self.version_reported = true;
+        return Poll::Ready(ConnectionHandlerEvent::Custom(
+          EpisubHandlerOutput::Negotiated(version),
+        ));
+      }
+    }
+
     // process inbound stream first
     let inbound_poll = self.process_inbound_poll(cx);
     if !matches!(inbound_poll, Poll::<EpisubHandlerEvent>::Pending) {
@@ -186,16 +300,21 @@ impl EpisubHandler {
     cx: &mut Context<'_>,
   ) -> Poll<EpisubHandlerEvent> {
     loop {
-      match std::mem::replace(
-        &mut self.inbound_substream,
-        Some(InboundSubstreamState::Poisoned),
-      ) {
+      match self
+        .inbound_substream
+        .replace(InboundSubstreamState::Poisoned)
+      {
         Some(InboundSubstreamState::WaitingInput(mut substream)) => {
           match substream.poll_next_unpin(cx) {
             Poll::Ready(Some(Ok(message))) => {
               self.inbound_substream =
                 Some(InboundSubstreamState::WaitingInput(substream));
-              return Poll::Ready(ConnectionHandlerEvent::Custom(message));
+              let version = self
+                .inbound_version
+                .expect("inbound substream is negotiated");
+              return Poll::Ready(ConnectionHandlerEvent::Custom(
+                EpisubHandlerOutput::Received(message, version),
+              ));
             }
             Poll::Ready(Some(Err(error))) => {
               warn!("inbound stream error: {:?}", error);
@@ -251,13 +370,25 @@ impl EpisubHandler {
     cx: &mut Context<'_>,
   ) -> Poll<EpisubHandlerEvent> {
     loop {
-      match std::mem::replace(
-        &mut self.outbound_substream,
-        Some(OutboundSubstreamState::Poisoned),
-      ) {
+      match self
+        .outbound_substream
+        .replace(OutboundSubstreamState::Poisoned)
+      {
         Some(OutboundSubstreamState::WaitingOutput(substream)) => {
           if let Some(msg) = self.outbound_queue.pop_front() {
             self.outbound_queue.shrink_to_fit();
+            let version = self
+              .outbound_version
+              .expect("outbound substream is negotiated");
+            if !version.supports(&msg) {
+              // handed back to the behaviour, it decides
+              // how to deliver it some other way.
+              self.outbound_substream =
+                Some(OutboundSubstreamState::WaitingOutput(substream));
+              return Poll::Ready(ConnectionHandlerEvent::Custom(
+                EpisubHandlerOutput::Unsupported(msg),
+              ));
+            }
             self.outbound_substream =
               Some(OutboundSubstreamState::PendingSend(substream, msg));
           } else {
@@ -293,7 +424,6 @@ impl EpisubHandler {
               return Poll::Ready(ConnectionHandlerEvent::Close(e));
             }
             Poll::Pending => {
-              self.keep_alive = KeepAlive::Yes;
               self.outbound_substream =
                 Some(OutboundSubstreamState::PendingSend(substream, message));
               break;
@@ -310,7 +440,6 @@ impl EpisubHandler {
               return Poll::Ready(ConnectionHandlerEvent::Close(e))
             }
             Poll::Pending => {
-              self.keep_alive = KeepAlive::Yes;
               self.outbound_substream =
                 Some(OutboundSubstreamState::PendingFlush(substream));
               break;
@@ -45,25 +45,30 @@
 //! }
 //! ```
 
-#[allow(clippy::module_inception)]
-
+#[allow(clippy::module_inception, clippy::four_forward_slashes)]
 mod rpc {
   include!(concat!(env!("OUT_DIR"), "/rpc.pb.rs"));
 }
 
+mod bans;
 mod behaviour;
 mod cache;
 mod codec;
 mod config;
 mod connection;
+mod diversity;
 mod error;
 mod handler;
+mod schedule;
+mod score;
 mod topic;
 mod tree;
 mod view;
 
+pub(crate) use behaviour::is_local_address;
 pub use {
+  bans::BanRecord,
   behaviour::{Episub, EpisubEvent},
   config::{Config, PeerAuthorizer},
-  error::{EpisubHandlerError, FormatError, PublishError, RpcError},
+  score::PeerScore,
 };
@@ -0,0 +1,129 @@
+use std::{
+  collections::VecDeque,
+  task::{Context, Poll},
+};
+
+/// Decides in which order topic meshes are polled.
+///
+/// Topics take turns in round-robin order. The topic in front is polled
+/// first, and it moves to the back once it has no more events or has
+/// yielded `budget` events in a row, so a busy topic can't starve the
+/// others.
+pub struct PollSchedule {
+  budget: usize,
+
+  /// Subscribed topics, the one in front has its turn.
+  order: VecDeque<String>,
+
+  /// Number of events yielded in a row by the topic in front.
+  streak: usize,
+}
+
+impl PollSchedule {
+  pub fn new(budget: usize) -> Self {
+    Self {
+      budget,
+      order: VecDeque::new(),
+      streak: 0,
+    }
+  }
+
+  /// Adds a topic at the end of the queue.
+  pub fn insert(&mut self, topic: String) {
+    self.order.push_back(topic);
+  }
+
+  /// Removes a topic, the next topic in the queue gets a fresh budget
+  /// if the removed one had its turn.
+  pub fn remove(&mut self, topic: &str) {
+    if self.order.front().map(String::as_str) == Some(topic) {
+      self.streak = 0;
+    }
+    self.order.retain(|t| t != topic);
+  }
+
+  /// Polls topics in turn until one of them yields an event.
+  pub fn poll<T>(
+    &mut self,
+    cx: &mut Context<'_>,
+    mut poll_topic: impl FnMut(&str, &mut Context<'_>) -> Poll<T>,
+  ) -> Poll<T> {
+    let mut exhausted = false;
+    for _ in 0..self.order.len() {
+      if self.streak < self.budget {
+        let topic = self.order.front().expect("order is not empty");
+        if let Poll::Ready(event) = poll_topic(topic, cx) {
+          self.streak += 1;
+          return Poll::Ready(event);
+        }
+      } else {
+        exhausted = true;
+      }
+
+      self.order.rotate_left(1);
+      self.streak = 0;
+    }
+
+    // a topic was skipped with events still pending,
+    // make sure we get polled again to deliver them.
+    if exhausted {
+      cx.waker().wake_by_ref();
+    }
+
+    Poll::Pending
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::PollSchedule,
+    futures::task::noop_waker_ref,
+    std::{
+      collections::HashMap,
+      task::{Context, Poll},
+    },
+  };
+
+  #[test]
+  fn flooded_topic_does_not_starve_others() {
+    let budget = 4;
+    let mut schedule = PollSchedule::new(budget);
+    schedule.insert("/busy".to_owned());
+    schedule.insert("/quiet".to_owned());
+    schedule.insert("/idle".to_owned());
+
+    // the busy topic always has another event, the quiet one has a single
+    // event and the idle one never has anything to deliver.
+    let mut pending = HashMap::from([("/quiet", 1)]);
+    let mut cx = Context::from_waker(noop_waker_ref());
+
+    let mut polls = 0;
+    loop {
+      polls += 1;
+      assert!(polls <= budget + 1, "quiet topic starved for {polls} polls");
+
+      let event = schedule.poll(&mut cx, |topic, _| match topic {
+        "/busy" => Poll::Ready(topic.to_owned()),
+        _ => match pending.get_mut(topic) {
+          Some(count) if *count > 0 => {
+            *count -= 1;
+            Poll::Ready(topic.to_owned())
+          }
+          _ => Poll::Pending,
+        },
+      });
+
+      if event == Poll::Ready("/quiet".to_owned()) {
+        break;
+      }
+    }
+
+    // the busy topic gets its turn back once the others are drained
+    let event = schedule.poll(&mut cx, |topic, _| match topic {
+      "/busy" => Poll::Ready(topic.to_owned()),
+      _ => Poll::Pending,
+    });
+    assert_eq!(event, Poll::Ready("/busy".to_owned()));
+  }
+}
@@ -0,0 +1,249 @@
+use {
+  super::config::Config,
+  libp2p::core::PeerId,
+  std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+  },
+};
+
+/// Penalty for a message that failed validation.
+const INVALID_MESSAGE_WEIGHT: f64 = 10.0;
+
+/// Penalty for a GRAFT asking for messages this node never announced.
+const USELESS_GRAFT_WEIGHT: f64 = 1.0;
+
+/// Penalty for an IHAVE that was not followed by the message in time
+/// after the link with its sender was grafted.
+const LATE_IHAVE_WEIGHT: f64 = 1.0;
+
+/// Penalty for a duplicate message. Some duplicates are expected while
+/// the broadcast tree converges, so only floods of them add up.
+const DUPLICATE_WEIGHT: f64 = 0.1;
+
+/// Reward for every hour of continuous connection uptime.
+const UPTIME_WEIGHT: f64 = 1.0;
+
+/// Uptime above this does not improve the score any further,
+/// so long-lived peers can't hide misbehaviour behind uptime.
+const MAX_UPTIME_REWARD: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// Rank penalty for every millisecond of round trip time, 100ms
+/// of latency weigh as much as an hour of uptime.
+const RTT_WEIGHT: f64 = 0.01;
+
+/// Penalties below this are negligible and are dropped
+/// together with scores of disconnected peers.
+const NEGLIGIBLE_PENALTY: f64 = 0.01;
+
+/// Kinds of misbehaviour that lower the score of a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Penalty {
+  InvalidMessage,
+  Duplicate,
+  UselessGraft,
+  LateIHave,
+}
+
+/// Track record of a peer, used to prefer well behaving peers when
+/// building the active view and the broadcast tree.
+///
+/// Penalties decay exponentially over time, so a peer that misbehaved
+/// once eventually gets back to a neutral score.
+#[derive(Debug, Clone)]
+pub struct PeerScore {
+  pub invalid_messages: f64,
+  pub duplicates: f64,
+  pub useless_grafts: f64,
+  pub late_ihaves: f64,
+
+  /// Smoothed round trip time measured by latency probes,
+  /// unknown until the peer answers the first probe.
+  pub rtt: Option<Duration>,
+  connected_since: Option<Instant>,
+  last_decay: Instant,
+
+  /// Nonce and send time of the probe awaiting a response.
+  probe: Option<(u64, Instant)>,
+}
+
+impl PeerScore {
+  fn new() -> Self {
+    Self {
+      invalid_messages: 0.0,
+      duplicates: 0.0,
+      useless_grafts: 0.0,
+      late_ihaves: 0.0,
+      rtt: None,
+      connected_since: None,
+      last_decay: Instant::now(),
+      probe: None,
+    }
+  }
+
+  /// Time since the current connection with the peer was established.
+  pub fn uptime(&self) -> Duration {
+    self
+      .connected_since
+      .map(|since| since.elapsed())
+      .unwrap_or_default()
+  }
+
+  /// Overall score of the peer, unknown peers score zero.
+  pub fn value(&self) -> f64 {
+    let uptime = self.uptime().min(MAX_UPTIME_REWARD).as_secs_f64() / 3600.0;
+    uptime * UPTIME_WEIGHT - self.penalties()
+  }
+
+  /// Score of the peer adjusted for its proximity to this node.
+  pub fn rank(&self) -> f64 {
+    let rtt = self.rtt.unwrap_or_default().as_secs_f64() * 1000.0;
+    self.value() - rtt * RTT_WEIGHT
+  }
+
+  /// Smooths round trip time samples the same way TCP does (RFC 6298),
+  /// so a single slow response does not reshape the mesh.
+  fn sample_rtt(&mut self, sample: Duration) {
+    self.rtt = Some(match self.rtt {
+      Some(rtt) => (rtt * 7 + sample) / 8,
+      None => sample,
+    });
+  }
+
+  fn penalties(&self) -> f64 {
+    self.invalid_messages * INVALID_MESSAGE_WEIGHT
+      + self.duplicates * DUPLICATE_WEIGHT
+      + self.useless_grafts * USELESS_GRAFT_WEIGHT
+      + self.late_ihaves * LATE_IHAVE_WEIGHT
+  }
+
+  fn decay(&mut self, half_life: Duration) {
+    let elapsed = self.last_decay.elapsed();
+    let factor = 0.5f64.powf(elapsed.as_secs_f64() / half_life.as_secs_f64());
+    self.invalid_messages *= factor;
+    self.duplicates *= factor;
+    self.useless_grafts *= factor;
+    self.late_ihaves *= factor;
+    self.last_decay = Instant::now();
+  }
+}
+
+/// Scores of all peers known to this node.
+///
+/// Peers are scored per node rather than per topic, a relay that
+/// misbehaves on one topic is not trusted on other topics either.
+/// Clones share the same scores, each topic mesh holds a clone.
+#[derive(Debug, Clone)]
+pub struct PeerScores {
+  half_life: Duration,
+  peers: Arc<Mutex<HashMap<PeerId, PeerScore>>>,
+}
+
+impl PeerScores {
+  pub fn new(config: &Config) -> Self {
+    Self {
+      half_life: config.score_half_life,
+      peers: Arc::new(Mutex::new(HashMap::new())),
+    }
+  }
+
+  /// Current score of a peer, unknown peers score zero.
+  pub fn score(&self, peer: &PeerId) -> f64 {
+    self.get(peer).map(|s| s.value()).unwrap_or(0.0)
+  }
+
+  /// Current score of a peer adjusted for its proximity to this node,
+  /// unknown peers rank zero.
+  pub fn rank(&self, peer: &PeerId) -> f64 {
+    self.get(peer).map(|s| s.rank()).unwrap_or(0.0)
+  }
+
+  pub fn rtt(&self, peer: &PeerId) -> Option<Duration> {
+    self.peers.lock().unwrap().get(peer).and_then(|s| s.rtt)
+  }
+
+  pub fn get(&self, peer: &PeerId) -> Option<PeerScore> {
+    let mut peers = self.peers.lock().unwrap();
+    peers.get_mut(peer).map(|score| {
+      score.decay(self.half_life);
+      score.clone()
+    })
+  }
+
+  pub fn all(&self) -> Vec<(PeerId, PeerScore)> {
+    let mut peers = self.peers.lock().unwrap();
+    peers
+      .iter_mut()
+      .map(|(peer, score)| {
+        score.decay(self.half_life);
+        (*peer, score.clone())
+      })
+      .collect()
+  }
+
+  pub fn penalize(&self, peer: PeerId, penalty: Penalty) {
+    let mut peers = self.peers.lock().unwrap();
+    let score = peers.entry(peer).or_insert_with(PeerScore::new);
+    score.decay(self.half_life);
+    match penalty {
+      Penalty::InvalidMessage => score.invalid_messages += 1.0,
+      Penalty::Duplicate => score.duplicates += 1.0,
+      Penalty::UselessGraft => score.useless_grafts += 1.0,
+      Penalty::LateIHave => score.late_ihaves += 1.0,
+    }
+  }
+
+  /// Starts a new latency probe of a connected peer and returns its
+  /// nonce. A probe that is still unanswered counts as a sample of twice
+  /// the current round trip time, unless the peer never answered any,
+  /// which is the case with peers on older protocol versions.
+  pub fn start_probe(&self, peer: &PeerId) -> Option<u64> {
+    let mut peers = self.peers.lock().unwrap();
+    let score = peers.get_mut(peer)?;
+    if let (Some(_), Some(rtt)) = (score.probe, score.rtt) {
+      score.sample_rtt(rtt * 2);
+    }
+
+    let nonce = rand::random();
+    score.probe = Some((nonce, Instant::now()));
+    Some(nonce)
+  }
+
+  /// Records the round trip time of a probe answered by a peer.
+  /// Responses that don't match the pending probe are ignored.
+  pub fn complete_probe(&self, peer: &PeerId, nonce: u64) {
+    let mut peers = self.peers.lock().unwrap();
+    if let Some(score) = peers.get_mut(peer) {
+      if let Some((expected, sent)) = score.probe {
+        if expected == nonce {
+          score.probe = None;
+          score.sample_rtt(sent.elapsed());
+        }
+      }
+    }
+  }
+
+  /// Starts counting uptime of a peer, unless it is already connected.
+  pub fn connected(&self, peer: PeerId) {
+    let mut peers = self.peers.lock().unwrap();
+    let score = peers.entry(peer).or_insert_with(PeerScore::new);
+    score.connected_since.get_or_insert_with(Instant::now);
+  }
+
+  /// Resets uptime of a peer once its last connection is closed, and
+  /// forgets disconnected peers with no penalties worth remembering.
+  pub fn disconnected(&self, peer: &PeerId) {
+    let mut peers = self.peers.lock().unwrap();
+    if let Some(score) = peers.get_mut(peer) {
+      score.connected_since = None;
+      score.probe = None;
+    }
+
+    let half_life = self.half_life;
+    peers.retain(|_, score| {
+      score.decay(half_life);
+      score.connected_since.is_some() || score.penalties() >= NEGLIGIBLE_PENALTY
+    });
+  }
+}
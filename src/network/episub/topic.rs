@@ -2,15 +2,21 @@ use {
   super::{
     behaviour::EpisubNetworkBehaviourAction,
     config::Config,
+    connection::ProtocolVersion,
+    diversity::ObservedAddresses,
     error::RpcError,
     rpc::{self, rpc::Action},
+    score::PeerScores,
     tree::PlumTree,
     view::{AddressablePeer, HyParView},
     EpisubEvent,
   },
   asynchronous_codec::Bytes,
   futures::FutureExt,
-  libp2p::{core::PeerId, swarm::NetworkBehaviourAction},
+  libp2p::{
+    core::PeerId,
+    swarm::{NetworkBehaviourAction, NotifyHandler},
+  },
   std::{
     collections::VecDeque,
     future::Future,
@@ -25,18 +31,39 @@ use {
 /// Each topic has its own HyParView instance that form their own cluster of
 /// nodes for message dissemination
 pub struct TopicMesh {
+  topic: String,
   tree: PlumTree,
   nodes: HyParView,
+  scores: PeerScores,
   local_node: AddressablePeer,
   out_events: VecDeque<EpisubNetworkBehaviourAction>,
 }
 
 impl TopicMesh {
-  pub fn new(topic: String, config: Config, local: AddressablePeer) -> Self {
+  pub fn new(
+    topic: String,
+    config: Config,
+    local: AddressablePeer,
+    scores: PeerScores,
+    observed: ObservedAddresses,
+  ) -> Self {
     TopicMesh {
-      tree: PlumTree::new(topic.clone(), config.clone(), local.peer_id),
+      tree: PlumTree::new(
+        topic.clone(),
+        config.clone(),
+        local.peer_id,
+        scores.clone(),
+      ),
       local_node: local.clone(),
-      nodes: HyParView::new(topic, config, local),
+      nodes: HyParView::new(
+        topic.clone(),
+        config,
+        local,
+        scores.clone(),
+        observed,
+      ),
+      scores,
+      topic,
       out_events: VecDeque::new(),
     }
   }
@@ -59,27 +86,45 @@ impl TopicMesh {
     self.nodes.inject_disconnect(peer_id, alive);
   }
 
+  /// Peers that were active in previous runs of this node, they are
+  /// used to rejoin the topic when bootstrap nodes are not available.
+  pub fn add_known_peers(
+    &mut self,
+    peers: impl Iterator<Item = AddressablePeer>,
+  ) {
+    self.nodes.add_passive_candidates(peers);
+  }
+
   pub fn initiate_join(&mut self, peer: AddressablePeer) {
     self.nodes.initiate_join(peer);
   }
 
-  pub fn publish(&mut self, id: u64, payload: Bytes) {
+  pub fn publish(&mut self, id: u64, payload: Bytes, compressed: bool) {
     debug!(
       "publishing message id {} with payload len {}",
       id,
       payload.len()
     );
-    self.tree.publish(id, payload);
+    self.tree.publish(id, payload, compressed);
   }
 
   /// Routes RPC calls to HyParView and MessageGraph from active nodes.
   /// Returns true if the message passes basic protocol validation and was
   /// ingested, otherwise returns false that the message
+  ///
+  /// The version is the protocol version negotiated with the peer, actions
+  /// that were introduced in later versions are a protocol violation.
+  #[allow(clippy::result_large_err)]
   pub fn inject_rpc_call(
     &mut self,
     peer_id: PeerId,
+    version: ProtocolVersion,
     event: rpc::Rpc,
   ) -> Result<(), RpcError> {
+    if !version.supports(&event) {
+      return Err(RpcError::UnsupportedAction(version));
+    }
+
     match event.action.unwrap() {
       Action::Join(rpc::Join { ttl, peer }) => {
         if let Ok(peer) = peer.try_into() {
@@ -133,8 +178,15 @@ impl TopicMesh {
       Action::ShuffleReply(params) => {
         self.nodes.inject_shuffle_reply(params);
       }
-      Action::Message(rpc::Message { id, hop, payload }) => {
-        self.tree.inject_message(peer_id, id, hop, payload);
+      Action::Message(rpc::Message {
+        id,
+        hop,
+        payload,
+        compressed,
+      }) => {
+        self
+          .tree
+          .inject_message(peer_id, id, hop, payload, compressed)?;
       }
       Action::Ihave(rpc::IHave { ihaves }) => {
         ihaves
@@ -149,6 +201,38 @@ impl TopicMesh {
       Action::Graft(rpc::Graft { ids }) => {
         self.tree.inject_graft(peer_id, ids);
       }
+
+      Action::Ping(rpc::Ping { nonce }) => {
+        self.out_events.push_back(
+          EpisubNetworkBehaviourAction::NotifyHandler {
+            peer_id,
+            handler: NotifyHandler::Any,
+            event: rpc::Rpc {
+              topic: self.topic.clone(),
+              action: Some(Action::Pong(rpc::Pong { nonce })),
+            }
+            .into(),
+          },
+        );
+      }
+      Action::Pong(rpc::Pong { nonce }) => {
+        self.scores.complete_probe(&peer_id, nonce);
+      }
+
+      Action::Direct(rpc::Direct {
+        id,
+        payload,
+        relay,
+        compressed,
+      }) => {
+        self.tree.inject_direct(
+          peer_id,
+          id,
+          payload,
+          relay.unwrap_or(false),
+          compressed,
+        )?;
+      }
     };
 
     Ok(())
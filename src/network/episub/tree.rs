@@ -6,21 +6,25 @@ use {
   super::{
     behaviour::EpisubNetworkBehaviourAction,
     cache::{ExpiringCache, Keyed, MessageInfo, MessageRecord},
+    error::RpcError,
     rpc,
+    score::{PeerScores, Penalty},
     Config,
     EpisubEvent,
   },
   asynchronous_codec::Bytes,
   libp2p::{core::PeerId, swarm::NotifyHandler},
   std::{
+    cmp::Ordering,
     collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
     future::Future,
+    io::Read,
     pin::Pin,
     task::{Context, Poll},
     time::Instant,
   },
-  tracing::{debug, error},
-  zstd::decode_all,
+  tracing::{debug, warn},
+  zstd::stream::read::Decoder,
 };
 
 pub struct PlumTree {
@@ -32,15 +36,28 @@ pub struct PlumTree {
   config: Config,
   observed: ExpiringCache<MessageInfo>,
   received: ExpiringCache<MessageRecord>,
+  scores: PeerScores,
+
+  /// Messages requested in GRAFTs, along with the peer that announced
+  /// them and the time of the request. Peers that don't deliver in time
+  /// are penalized.
+  grafted: HashMap<u64, (PeerId, Instant)>,
   out_events: VecDeque<EpisubNetworkBehaviourAction>,
 }
 
 impl PlumTree {
-  pub fn new(topic: String, config: Config, local_node: PeerId) -> Self {
+  pub fn new(
+    topic: String,
+    config: Config,
+    local_node: PeerId,
+    scores: PeerScores,
+  ) -> Self {
     PlumTree {
       topic,
       config,
       local_node,
+      scores,
+      grafted: HashMap::new(),
       lazy: HashSet::new(),
       eager: HashSet::new(),
       last_tick: Instant::now(),
@@ -50,20 +67,33 @@ impl PlumTree {
     }
   }
 
-  /// Called when the peer sampling service (HyparView) activates a peer
+  /// Called when the peer sampling service (HyparView) activates a peer.
+  /// Peers with a poor track record start as lazy push peers, unless
+  /// there is no other eager peer.
   pub fn inject_neighbor_up(&mut self, peer: PeerId) {
-    self.eager.insert(peer);
+    if self.scores.score(&peer) < self.config.eager_score_threshold
+      && !self.eager.is_empty()
+    {
+      debug!("adding low scoring peer {} as lazy", peer);
+      self.lazy.insert(peer);
+    } else {
+      self.eager.insert(peer);
+    }
   }
 
   /// Called when the peer sampling service (HyparView) deactivates a peer
   pub fn inject_neighbor_down(&mut self, peer: PeerId) {
     self.eager.remove(&peer);
     self.lazy.remove(&peer);
+    self.grafted.retain(|_, (sender, _)| sender != &peer);
   }
 
-  pub fn publish(&mut self, id: u64, payload: Bytes) {
+  /// Broadcasts a message to eager peers. Message ids are derived from
+  /// message contents, so a message that was already received or
+  /// announced by another node is a duplicate and is not sent again.
+  pub fn publish(&mut self, id: u64, payload: Bytes, compressed: bool) {
     if let Some(msg) = self.received.get(&id) {
-      error!(
+      debug!(
         "not publishing a message with id {}, received previously from node {}",
         id, msg.sender
       );
@@ -71,8 +101,8 @@ impl PlumTree {
     }
 
     if let Some(msg) = self.observed.get(&id) {
-      error!(
-        "refusing to send a message with id {}, observed previously by node {}",
+      debug!(
+        "not publishing a message with id {}, observed previously by node {}",
         id, msg.sender
       );
       return;
@@ -83,6 +113,7 @@ impl PlumTree {
       payload,
       hop: 1,
       sender: self.local_node,
+      compressed,
     };
 
     for enode in &self.eager {
@@ -95,7 +126,8 @@ impl PlumTree {
           event: rpc::Rpc {
             topic: self.topic.clone(),
             action: Some(rpc::rpc::Action::Message(message.clone().into())),
-          },
+          }
+          .into(),
         });
     }
 
@@ -107,31 +139,84 @@ impl PlumTree {
     self.received.insert(MessageRecord { hop: 0, ..message });
   }
 
+  /// Invoked when a peer sends a message straight to this node instead
+  /// of broadcasting it through the tree. Such messages are handed over
+  /// to the local node only, unless the sender is not a member of the
+  /// topic and asks us to relay the message, then it is also broadcast
+  /// as if it was published by this node.
+  #[allow(clippy::result_large_err)]
+  pub fn inject_direct(
+    &mut self,
+    peer_id: PeerId,
+    id: u64,
+    payload: Bytes,
+    relay: bool,
+    compressed: Option<bool>,
+  ) -> Result<(), RpcError> {
+    debug!("received direct message from {} with id {}", peer_id, id);
+    let compressed = compressed.unwrap_or(self.config.enable_compression);
+    let out = match self.decompress(&payload, compressed)? {
+      Some(out) => out,
+      None => return Ok(()),
+    };
+    self
+      .out_events
+      .push_back(EpisubNetworkBehaviourAction::GenerateEvent(
+        EpisubEvent::Message {
+          topic: self.topic.clone(),
+          id,
+          payload: out,
+        },
+      ));
+
+    if relay {
+      self.publish(id, payload, compressed);
+    }
+
+    Ok(())
+  }
+
+  #[allow(clippy::result_large_err)]
   pub fn inject_message(
     &mut self,
     peer_id: PeerId,
     id: u64,
     hop: u32,
     payload: Bytes,
-  ) {
+    compressed: Option<bool>,
+  ) -> Result<(), RpcError> {
+    let compressed = compressed.unwrap_or(self.config.enable_compression);
     debug!(
       "received message from {} with id {} [hop {}]",
       peer_id, id, hop
     );
 
+    // a message that we have asked this peer for in a GRAFT
+    let fulfilled = matches!(
+      self.grafted.get(&id),
+      Some((sender, _)) if sender == &peer_id
+    );
+    if fulfilled {
+      self.grafted.remove(&id);
+    }
+
     // if we don't have this message in the message cache
     // it means that we're seeing it for the first time,
     // then forward it to all eager push nodes.
-    if self.received.insert(MessageRecord {
-      id,
-      hop,
-      payload: payload.clone(),
-      sender: peer_id,
-    }) {
-      let out = match self.config.enable_compression {
-        true => Bytes::from(decode_all(payload.as_ref()).unwrap()),
-        false => payload.clone(),
+    if self.received.get(&id).is_none() {
+      // a payload that can't be decompressed is never cached or
+      // forwarded, the sender gets banned if it is malformed.
+      let out = match self.decompress(&payload, compressed)? {
+        Some(out) => out,
+        None => return Ok(()),
       };
+      self.received.insert(MessageRecord {
+        id,
+        hop,
+        payload: payload.clone(),
+        sender: peer_id,
+        compressed,
+      });
       self
         .out_events
         .push_back(EpisubNetworkBehaviourAction::GenerateEvent(
@@ -148,6 +233,7 @@ impl PlumTree {
           payload,
           id,
           hop: hop + 1,
+          compressed: Some(compressed),
         })),
       };
 
@@ -160,12 +246,22 @@ impl PlumTree {
           EpisubNetworkBehaviourAction::NotifyHandler {
             peer_id: *peer,
             handler: NotifyHandler::Any,
-            event: message.clone(),
+            event: message.clone().into(),
           },
         );
         debug!("sending message {} to peer {}", id, peer);
       }
-    } else if self.config.optimize_sender_tree {
+    } else {
+      // some duplicates are expected until cycles in the tree
+      // are pruned, but a peer that keeps sending them is
+      // wasting our bandwidth.
+      if !fulfilled {
+        self.scores.penalize(peer_id, Penalty::Duplicate);
+      }
+      if !self.config.optimize_sender_tree {
+        return Ok(());
+      }
+
       // this is a duplicate message, it means that we are
       // having a cycle in the node connectivity graph. The
       // sender should be moved to lazy push peers and notified
@@ -179,12 +275,15 @@ impl PlumTree {
             event: rpc::Rpc {
               topic: self.topic.clone(),
               action: Some(rpc::rpc::Action::Prune(rpc::Prune {})),
-            },
+            }
+            .into(),
           },
         );
         debug!("pruning link with {}", peer_id);
       }
     }
+
+    Ok(())
   }
 
   pub fn inject_ihave(&mut self, peer_id: PeerId, id: u64, hop: u32) {
@@ -201,6 +300,13 @@ impl PlumTree {
   }
 
   pub fn inject_graft(&mut self, peer_id: PeerId, ids: Vec<u64>) {
+    // we only announce messages that we have, asking for
+    // messages that are not there is a waste of our time.
+    if !ids.is_empty() && ids.iter().all(|id| self.received.get(id).is_none()) {
+      debug!("useless graft from {}: {:?}", peer_id, ids);
+      self.scores.penalize(peer_id, Penalty::UselessGraft);
+    }
+
     // upgrade to eager node after graft
     self.lazy.remove(&peer_id);
     self.eager.insert(peer_id);
@@ -218,13 +324,54 @@ impl PlumTree {
             event: rpc::Rpc {
               topic: self.topic.clone(),
               action: Some(rpc::rpc::Action::Message(msg.clone().into())),
-            },
+            }
+            .into(),
           })
       });
   }
 }
 
 impl PlumTree {
+  /// Restores the original payload of a message received from a peer.
+  /// Senders flag compressed payloads, so peers with different compression
+  /// settings can still talk to each other.
+  ///
+  /// Decompression is streamed and stops as soon as the output grows
+  /// past the configured maximum, so a small malicious payload can't
+  /// inflate into an arbitrary amount of memory.
+  ///
+  /// Payloads that grow past the maximum are dropped without blaming the
+  /// sender. It may only be forwarding a message from a node that has a
+  /// higher limit configured.
+  #[allow(clippy::result_large_err)]
+  fn decompress(
+    &self,
+    payload: &Bytes,
+    compressed: bool,
+  ) -> Result<Option<Bytes>, RpcError> {
+    if !compressed {
+      return Ok(Some(payload.clone()));
+    }
+
+    let limit = self.config.max_decompressed_size;
+    let mut out = Vec::new();
+    Decoder::new(payload.as_ref())
+      .map_err(|_| RpcError::MalformedPayload)?
+      .take(limit as u64 + 1)
+      .read_to_end(&mut out)
+      .map_err(|_| RpcError::MalformedPayload)?;
+
+    if out.len() > limit {
+      warn!(
+        "Dropping message on {} that decompresses to more than {} bytes",
+        self.topic, limit
+      );
+      return Ok(None);
+    }
+
+    Ok(Some(out.into()))
+  }
+
   /// send IHAVEs to all lazy push nodes
   fn publish_ihaves(&mut self) {
     let time_range_begin = Instant::now() - self.config.lazy_push_window;
@@ -250,7 +397,8 @@ impl PlumTree {
               action: Some(rpc::rpc::Action::Ihave(rpc::IHave {
                 ihaves: received.clone(),
               })),
-            },
+            }
+            .into(),
           })
       });
     }
@@ -276,7 +424,9 @@ impl PlumTree {
       // graft the connection to the peer that told us about the missing
       // message. Also we replace the eager push link if the hop count
       // in the observed messages is significantly lower than what we
-      // received from the eager push nodes.
+      // received from the eager push nodes, or if the lazy push peer
+      // is much closer to us than the eager one over a path that is
+      // not any longer.
       let time_range_begin = Instant::now() - self.config.lazy_push_window;
       let time_range_end = Instant::now() - 2 * self.config.tick_frequency;
       let expected_ihaves: HashSet<_> = self
@@ -288,9 +438,15 @@ impl PlumTree {
       for observed in expected_ihaves {
         match self.received.get(&observed.key()) {
           Some(received) => {
-            if received.hop.saturating_sub(observed.hop)
+            let shorter = received.hop.saturating_sub(observed.hop)
               >= self.config.hop_optimization_factor
-              && observed.hop != 0
+              && observed.hop != 0;
+            let closer = observed.hop <= received.hop
+              && self.is_closer(&observed.sender, &received.sender);
+            if (shorter || closer)
+              && observed.sender != received.sender
+              && self.scores.score(&observed.sender)
+                >= self.config.eager_score_threshold
             {
               debug!(
                 "path for message {} from {} is better than {} ({}:{})",
@@ -302,8 +458,9 @@ impl PlumTree {
               );
 
               // we have the message, so no need to request it again,
-              // but the path it took to reach us is much shorter, so
-              // just graft the connection but don't ask for the message.
+              // but the path it took to reach us is much shorter or
+              // faster, so just graft the connection but don't ask for the
+              // message.
               if let Entry::Vacant(v) = grafts.entry(observed.sender) {
                 // this will make this sender an active push node again,
                 // if the path indeed is faster than the old one, then
@@ -323,6 +480,10 @@ impl PlumTree {
             );
             // we have a missing message, so we need to request it from
             // the node we are grafting our connection to.
+            self
+              .grafted
+              .entry(observed.id)
+              .or_insert_with(|| (observed.sender, Instant::now()));
             match grafts.entry(observed.sender) {
               Entry::Vacant(v) => {
                 v.insert(vec![observed.id]);
@@ -345,13 +506,77 @@ impl PlumTree {
             event: rpc::Rpc {
               topic: self.topic.clone(),
               action: Some(rpc::rpc::Action::Graft(rpc::Graft { ids })),
-            },
+            }
+            .into(),
           })
       });
     }
 
+    self.penalize_unfulfilled_grafts();
+    self.demote_low_scoring_peers();
     self.prune_history();
   }
+
+  /// Tells whether a peer is closer to this node than another one by at
+  /// least the configured threshold. Peers that haven't answered a
+  /// latency probe yet are not compared.
+  fn is_closer(&self, peer: &PeerId, than: &PeerId) -> bool {
+    match (self.scores.rtt(peer), self.scores.rtt(than)) {
+      (Some(peer), Some(than)) => {
+        than.saturating_sub(peer) >= self.config.rtt_optimization_threshold
+      }
+      _ => false,
+    }
+  }
+
+  /// Peers that announced a message in an IHAVE are expected to deliver
+  /// it within the lazy push window once asked for it in a GRAFT.
+  fn penalize_unfulfilled_grafts(&mut self) {
+    let deadline = Instant::now() - self.config.lazy_push_window;
+    let scores = &self.scores;
+    self.grafted.retain(|id, (sender, requested)| {
+      if *requested > deadline {
+        return true;
+      }
+      debug!("message {} was not delivered in time by {}", id, sender);
+      scores.penalize(*sender, Penalty::LateIHave);
+      false
+    });
+  }
+
+  /// Moves eager peers that score below the threshold to lazy peers.
+  /// The best scoring eager peer is always kept, so the tree does not
+  /// fall back to lazy push entirely when all peers score poorly.
+  fn demote_low_scoring_peers(&mut self) {
+    let scored: Vec<_> = self
+      .eager
+      .iter()
+      .map(|peer| (*peer, self.scores.score(peer)))
+      .collect();
+
+    let best = scored
+      .iter()
+      .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal))
+      .map(|(peer, _)| *peer);
+
+    for (peer, score) in scored {
+      if score < self.config.eager_score_threshold && Some(peer) != best {
+        debug!("demoting peer {} to lazy, score: {}", peer, score);
+        self.inject_prune(peer);
+        self.out_events.push_back(
+          EpisubNetworkBehaviourAction::NotifyHandler {
+            peer_id: peer,
+            handler: NotifyHandler::Any,
+            event: rpc::Rpc {
+              topic: self.topic.clone(),
+              action: Some(rpc::rpc::Action::Prune(rpc::Prune {})),
+            }
+            .into(),
+          },
+        );
+      }
+    }
+  }
 }
 
 impl Future for PlumTree {
@@ -6,9 +6,11 @@ use {
   super::{
     behaviour::EpisubNetworkBehaviourAction,
     config::Config,
+    diversity::{DiversityMetrics, ObservedAddresses, Subnet},
     error::FormatError,
     handler::EpisubHandler,
     rpc,
+    score::PeerScores,
     EpisubEvent,
   },
   futures::Future,
@@ -20,7 +22,11 @@ use {
       NotifyHandler,
     },
   },
-  rand::{distributions::Uniform, prelude::IteratorRandom, Rng},
+  rand::{
+    distributions::Uniform,
+    prelude::{IteratorRandom, SliceRandom},
+    Rng,
+  },
   std::{
     collections::{HashSet, VecDeque},
     pin::Pin,
@@ -52,6 +58,7 @@ use {
 /// The HyParView protocol maintains two distinct views at each node:
 ///   - a small active view, of size log(n) + c,
 ///   - and a larger passive view, of size k(log(n) + c).
+///
 /// where n is the total number of online nodes participating in the protocol.
 pub struct HyParView {
   config: Config,
@@ -60,6 +67,17 @@ pub struct HyParView {
   active: HashSet<AddressablePeer>,
   passive: HashSet<AddressablePeer>,
 
+  /// Track record of peers, used to decide which peers
+  /// are promoted to or evicted from the active view.
+  scores: PeerScores,
+
+  /// Peers kept out of the views to preserve subnet diversity.
+  diversity: DiversityMetrics,
+
+  /// Addresses connections with peers were made on, they decide
+  /// which subnet a peer belongs to.
+  observed: ObservedAddresses,
+
   last_tick: Instant,
 
   // timestamp of the last outgoing periodic
@@ -72,10 +90,19 @@ pub struct HyParView {
 
 /// Public methods
 impl HyParView {
-  pub fn new(topic: String, config: Config, local: AddressablePeer) -> Self {
+  pub fn new(
+    topic: String,
+    config: Config,
+    local: AddressablePeer,
+    scores: PeerScores,
+    observed: ObservedAddresses,
+  ) -> Self {
     Self {
       config,
       topic,
+      scores,
+      diversity: DiversityMetrics::default(),
+      observed,
       last_tick: Instant::now(),
       last_shuffle: Instant::now(),
       active: HashSet::new(),
@@ -130,47 +157,99 @@ impl HyParView {
   pub fn starved(&self) -> bool {
     self.active.len() < self.config.min_active_view_size()
   }
+
+  pub fn diversity_metrics(&self) -> DiversityMetrics {
+    self.diversity
+  }
 }
 
 impl HyParView {
   /// This is invoked when a node sends us a JOIN request,
   /// it will see if the active view is full, and if so, removes
-  /// a random node from the active view and makes space for the new
-  /// node.
+  /// the lowest ranking node from the active view and makes space
+  /// for the new node. Nodes rank lower when they misbehave or
+  /// are far away from us.
   fn free_up_active_slot(&mut self) {
     if self.overconnected() {
-      let random = self.passive.iter().choose(&mut rand::thread_rng()).cloned();
-      if let Some(random) = random {
+      let worst = self.pick_by_rank(self.active.iter(), |a, b| b < a);
+      if let Some(worst) = worst {
         debug!(
-          "Moving peer {} from active view to passive.",
-          random.peer_id
+          "Moving peer {} from active view to passive, rank: {}",
+          worst.peer_id,
+          self.scores.rank(&worst.peer_id)
         );
-        self.active.remove(&random);
+        self.active.remove(&worst);
         self.out_events.push_back(
           EpisubNetworkBehaviourAction::NotifyHandler {
-            peer_id: random.peer_id,
+            peer_id: worst.peer_id,
             handler: NotifyHandler::Any,
             event: rpc::Rpc {
               topic: self.topic.clone(),
               action: Some(rpc::rpc::Action::Disconnect(rpc::Disconnect {
                 alive: true,
               })),
-            },
+            }
+            .into(),
           },
         );
         self
           .out_events
           .push_back(EpisubNetworkBehaviourAction::GenerateEvent(
-            EpisubEvent::PeerRemoved(random.peer_id),
+            EpisubEvent::PeerRemoved(worst.peer_id),
           ));
-        self.add_node_to_passive_view(random);
+        self.add_node_to_passive_view(worst);
       }
     }
   }
+
+  /// Returns the subnet that would have more peers in
+  /// the active view than allowed, if the peer was added.
+  fn crowded_subnet(&self, peer: &AddressablePeer) -> Option<Subnet> {
+    let limit = self.config.max_active_peers_per_subnet();
+    Subnet::crowded(self.active(), peer, limit, &self.observed)
+  }
+
+  /// Picks the peer whose rank is preferred over all others by the
+  /// `better` predicate. Peers with equal ranks, most commonly peers
+  /// we know nothing about yet, are picked at random.
+  fn pick_by_rank<'a>(
+    &self,
+    peers: impl Iterator<Item = &'a AddressablePeer>,
+    better: impl Fn(f64, f64) -> bool,
+  ) -> Option<AddressablePeer> {
+    let mut peers: Vec<_> = peers.collect();
+    peers.shuffle(&mut rand::thread_rng());
+
+    let mut best: Option<(&AddressablePeer, f64)> = None;
+    for peer in peers {
+      let rank = self.scores.rank(&peer.peer_id);
+      if best.map(|(_, r)| better(rank, r)).unwrap_or(true) {
+        best = Some((peer, rank));
+      }
+    }
+    best.map(|(peer, _)| peer.clone())
+  }
 }
 
 /// public handlers of HyParView protocol control messages
 impl HyParView {
+  /// Adds peers known from previous runs of this node to the passive
+  /// view, as long as there is room for them. They are promoted to the
+  /// active view like any other passive peer when the topic starves.
+  pub fn add_passive_candidates(
+    &mut self,
+    peers: impl Iterator<Item = AddressablePeer>,
+  ) {
+    for peer in peers {
+      if self.passive.len() >= self.config.max_passive_view_size() {
+        break;
+      }
+      if !self.active.contains(&peer) {
+        self.add_node_to_passive_view(peer);
+      }
+    }
+  }
+
   pub fn initiate_join(&mut self, peer: AddressablePeer) {
     self
       .out_events
@@ -183,7 +262,8 @@ impl HyParView {
             ttl: self.config.active_walk_length() as u32,
             peer: self.local_node.clone().into(),
           })),
-        },
+        }
+        .into(),
       });
   }
 
@@ -220,7 +300,8 @@ impl HyParView {
                 peer: peer.clone().into(),
                 ttl,
               })),
-            },
+            }
+            .into(),
           },
         );
       }
@@ -239,7 +320,10 @@ impl HyParView {
       return;
     }
 
-    if ttl == 0 && !self.is_active(&peer.peer_id) {
+    if ttl == 0
+      && !self.is_active(&peer.peer_id)
+      && self.crowded_subnet(&peer).is_none()
+    {
       // if we're full, free up a slot and move a node to passive.
       self.free_up_active_slot();
     }
@@ -265,7 +349,8 @@ impl HyParView {
                 peer: peer.clone().into(),
                 ttl: (ttl - 1) as u32,
               })),
-            },
+            }
+            .into(),
           },
         );
       }
@@ -285,7 +370,9 @@ impl HyParView {
       // that have zero active peers, in that case we make
       // space for them by moving one of the active peers
       // to the passive view and accepting their request.
-      if priority == rpc::neighbor::Priority::High as i32 {
+      if priority == rpc::neighbor::Priority::High as i32
+        && self.crowded_subnet(&peer).is_none()
+      {
         self.free_up_active_slot();
         self.add_node_to_active_view(peer, false);
       } else {
@@ -407,7 +494,8 @@ impl HyParView {
                 nodes: nodes.into_iter().map(|n| n.into()).collect(),
                 ttl: ttl - 1,
               })),
-            },
+            }
+            .into(),
           },
         );
       } else {
@@ -419,19 +507,18 @@ impl HyParView {
   }
 
   pub fn inject_shuffle_reply(&mut self, params: rpc::ShuffleReply) {
-    let nodes = params
+    let nodes: Vec<AddressablePeer> = params
       .nodes
       .into_iter()
       .filter_map(|n| n.try_into().ok())
       .collect();
 
-    // merge both lists of passive peers
-    self.passive = self.passive.union(&nodes).cloned().collect();
-
-    // and remove any excess nodes that put us
-    // above the maximum passive view size.
-    while self.passive.len() > self.config.max_passive_view_size() {
-      self.passive.drain().next();
+    // merge both lists of passive peers, this also removes any
+    // excess nodes that put us above the maximum passive view size.
+    for node in nodes {
+      if !self.active.contains(&node) {
+        self.add_node_to_passive_view(node);
+      }
     }
   }
 }
@@ -446,6 +533,34 @@ impl HyParView {
       return;
     }
 
+    if let Some(subnet) = self.crowded_subnet(&node) {
+      self.diversity.active_rejections += 1;
+      debug!(
+        "Keeping peer {} out of active view of {}: too many peers in subnet {}",
+        node.peer_id, self.topic, subnet
+      );
+
+      // the peer has already added us to its active view,
+      // tell it to move us to its passive view.
+      if !initiator {
+        self.out_events.push_back(
+          EpisubNetworkBehaviourAction::NotifyHandler {
+            peer_id: node.peer_id,
+            handler: NotifyHandler::Any,
+            event: rpc::Rpc {
+              topic: self.topic.clone(),
+              action: Some(rpc::rpc::Action::Disconnect(rpc::Disconnect {
+                alive: true,
+              })),
+            }
+            .into(),
+          },
+        );
+      }
+      self.add_node_to_passive_view(node);
+      return;
+    }
+
     if self.active.insert(node.clone()) {
       debug!("Adding peer to active view: {:?}", node);
       if initiator {
@@ -472,7 +587,8 @@ impl HyParView {
                   false => rpc::neighbor::Priority::Low.into(),
                 },
               })),
-            },
+            }
+            .into(),
           },
         );
       }
@@ -486,6 +602,19 @@ impl HyParView {
   }
 
   fn add_node_to_passive_view(&mut self, node: AddressablePeer) {
+    let limit = self.config.max_passive_peers_per_subnet();
+    if let Some(subnet) =
+      Subnet::crowded(self.passive(), &node, limit, &self.observed)
+    {
+      self.diversity.passive_rejections += 1;
+      debug!(
+        "Keeping peer {} out of passive view of {}: too many peers in subnet \
+         {}",
+        node.peer_id, self.topic, subnet
+      );
+      return;
+    }
+
     if node.peer_id != self.local_node.peer_id {
       trace!("Adding peer to passive view: {:?}", node);
       self.passive.insert(node);
@@ -525,7 +654,8 @@ impl HyParView {
               .map(|a| a.into())
               .collect(),
           })),
-        },
+        }
+        .into(),
       });
   }
 
@@ -560,17 +690,18 @@ impl HyParView {
           action: Some(rpc::rpc::Action::ShuffleReply(rpc::ShuffleReply {
             nodes: nodes.into_iter().map(|n| n.into()).collect(),
           })),
-        },
+        }
+        .into(),
       });
   }
 
-  fn maybe_move_random_passive_to_active(&mut self) {
+  fn maybe_move_best_passive_to_active(&mut self) {
     if self.starved() {
-      let random = self.passive.iter().choose(&mut rand::thread_rng()).cloned();
-      if let Some(random) = random {
-        self.passive.remove(&random);
-        trace!("removing peer {:?} from passive view", random);
-        self.add_node_to_active_view(random, true);
+      let best = self.pick_by_rank(self.passive.iter(), |a, b| a > b);
+      if let Some(best) = best {
+        self.passive.remove(&best);
+        trace!("removing peer {:?} from passive view", best);
+        self.add_node_to_active_view(best, true);
       }
     }
   }
@@ -612,7 +743,7 @@ impl Future for HyParView {
       // if we are blow the desired number of active nodes,
       // and we have just learned about new passive nodes,
       // try to topup the active nodes
-      self.maybe_move_random_passive_to_active();
+      self.maybe_move_best_passive_to_active();
       self.last_tick = Instant::now();
     }
 
@@ -1,8 +1,15 @@
+mod envelope;
 mod episub;
+mod routing;
+mod seeds;
 
 use {
-  crate::primitives::{Keypair, Message, Subscription},
-  episub::{Config, Episub, EpisubEvent, PeerAuthorizer},
+  crate::{
+    primitives::{Keypair, Message, Pubkey, Subscription},
+    storage::{KnownPeer, PersistentStorage},
+  },
+  envelope::Envelope,
+  episub::{is_local_address, Config, Episub, EpisubEvent, PeerAuthorizer},
   futures::StreamExt,
   libp2p::{
     core::{muxing::StreamMuxerBox, transport::Boxed, upgrade::Version},
@@ -18,18 +25,71 @@ use {
     Swarm,
     Transport,
   },
+  routing::Router,
+  serde::{Deserialize, Serialize},
   std::time::Duration,
-  tokio::sync::mpsc::{
-    error::SendError,
-    unbounded_channel,
-    UnboundedReceiver,
-    UnboundedSender,
+  tokio::{
+    sync::{
+      mpsc::{
+        error::SendError,
+        unbounded_channel,
+        UnboundedReceiver,
+        UnboundedSender,
+      },
+      oneshot,
+    },
+    time::{interval, interval_at, Instant},
   },
   tracing::{debug, error, warn},
 };
+pub use {
+  episub::{BanRecord, PeerScore},
+  routing::ShardConfig,
+  seeds::Seed,
+};
 
 type BoxedTransport = Boxed<(PeerId, StreamMuxerBox)>;
 
+/// Largest gossip payload on the wire, after compression.
+const MAX_TRANSMIT_SIZE: usize = 64 * 1024;
+
+/// Largest gossip payload after decompression.
+const MAX_DECOMPRESSED_SIZE: usize = 1024 * 1024;
+
+/// Room for the topic, the signed envelope and the gossip
+/// framing around the content of a relayed message.
+const MESSAGE_OVERHEAD: usize = 1024;
+
+/// Largest message content that can be relayed. Content of this size
+/// stays within the transmit size limit even if it doesn't compress,
+/// and that limit is below the decompressed size limit.
+pub const MAX_MESSAGE_SIZE: usize = MAX_TRANSMIT_SIZE - MESSAGE_OVERHEAD;
+
+/// How often nodes announce the message shards they are members of.
+const SHARDS_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often nodes announce again the topics they host subscribers on,
+/// announcements of other nodes that are not refreshed expire.
+const SUBSCRIPTIONS_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Maximum number of topics in a single subscriptions announcement,
+/// keeps announcements below the gossip transmit size limit.
+const SUBSCRIPTIONS_PER_ANNOUNCEMENT: usize = 512;
+
+/// How often scores of known peers and mesh metrics are written to the log.
+const MESH_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often active peers are saved to the address book.
+const ADDRESS_BOOK_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Peers that were not seen for longer than this
+/// are removed from the address book.
+const ADDRESS_BOOK_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How often bootstrap seeds are resolved and dialed again
+/// while the node is not connected to enough peers.
+const SEED_LOOKUP_INTERVAL: Duration = Duration::from_secs(30);
+
 async fn create_transport(
   keypair: &Keypair,
 ) -> std::io::Result<BoxedTransport> {
@@ -66,32 +126,100 @@ async fn create_transport(
 pub enum NetworkEvent {
   MessageReceived(Message),
   MessageAcknowledged(Multihash),
-  SubscriptionReceived(Subscription),
+  SubscriptionReceived(Subscription, Pubkey),
+  SubscriptionDropped(Subscription, Pubkey),
+}
+
+/// Announcements published on the subscriptions topic.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum SubscriptionUpdate {
+  /// The sending node started hosting subscribers on a topic.
+  /// It can be reached directly on the attached addresses.
+  Created {
+    topic: Subscription,
+    node: Pubkey,
+    addresses: Vec<Multiaddr>,
+  },
+
+  /// The last subscriber on a topic has left the sending node.
+  Dropped { topic: Subscription, node: Pubkey },
+
+  /// Periodic refresh of topics the sending node hosts subscribers on.
+  /// Announced subscriptions expire unless they are refreshed.
+  Hosted {
+    node: Pubkey,
+    topics: Vec<Subscription>,
+    addresses: Vec<Multiaddr>,
+  },
+
+  /// The message shards the sending node is a member of. Nodes outside
+  /// of a shard hand messages over to its members.
+  Shards {
+    node: Pubkey,
+
+    /// Total number of shards the sending node splits the network into.
+    count: u16,
+    shards: Vec<u16>,
+    addresses: Vec<Multiaddr>,
+  },
+}
+
+/// Published on the ack topic when a client confirms that it has processed
+/// a message. The envelope it is sealed in identifies the acknowledging
+/// node, which only does so for messages in its own mailbox.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Acknowledgement {
+  topic: Subscription,
+  hash: Multihash,
+}
+
+impl SubscriptionUpdate {
+  /// The node the update is about, it must also be its signer.
+  fn node(&self) -> &Pubkey {
+    match self {
+      Self::Created { node, .. } => node,
+      Self::Dropped { node, .. } => node,
+      Self::Hosted { node, .. } => node,
+      Self::Shards { node, .. } => node,
+    }
+  }
 }
 
 // this is a bug in clippy, I filed an issue on GH:
 // https://github.com/rust-lang/rust-clippy/issues/8321
 // remove this when the issue gets closed.
 #[allow(clippy::large_enum_variant)]
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub enum NetworkCommand {
   Connect(Multiaddr),
   GossipMessage(Message),
-  GossipACK(Multihash),
+  GossipACK(Subscription, Multihash),
   GossipSubscription(Subscription),
+  GossipUnsubscription(Subscription),
+  Unsubscribe(String),
+  Unban(PeerId),
+  Scores(oneshot::Sender<Vec<(PeerId, PeerScore)>>),
 }
 
 pub struct Network {
   netin: UnboundedReceiver<NetworkEvent>,
   netout: UnboundedSender<NetworkCommand>,
+
+  /// Gossip topics joined on startup, left when the node shuts down.
+  topics: Vec<String>,
 }
 
+// the error type carries the unsent command, which is large
+// because of the message variant.
+#[allow(clippy::result_large_err)]
 impl Network {
   pub async fn new(
     network_id: String,
     keypair: Keypair,
     listenaddrs: impl Iterator<Item = Multiaddr>,
-    bootstrap: Vec<Multiaddr>,
+    bootstrap: Vec<Seed>,
+    shards: ShardConfig,
+    storage: PersistentStorage,
   ) -> std::io::Result<Self> {
     let id = identity::Keypair::Ed25519(
       identity::ed25519::SecretKey::from_bytes(
@@ -106,6 +234,7 @@ impl Network {
     // moment it is an allow-all before we introduce a whitelisting
     // mechanism, either through stake, or some other means.
     let authorizer = PeerAuthorizer::new(move |_, _| true);
+    let local_node = keypair.public();
 
     let mut swarm = Swarm::new(
       create_transport(&keypair).await?,
@@ -113,7 +242,8 @@ impl Network {
         authorizer,
         active_view_factor: 4,
         network_size: 20,
-        max_transmit_size: 64 * 1024, // 64kb
+        max_transmit_size: MAX_TRANSMIT_SIZE,
+        max_decompressed_size: MAX_DECOMPRESSED_SIZE,
         history_window: Duration::from_secs(30),
         lazy_push_window: Duration::from_secs(5),
         shuffle_probability: 0.3, // shuffle only 30% of peers at once
@@ -122,12 +252,51 @@ impl Network {
       id.public().to_peer_id(),
     );
 
-    // This is the topic where all messages send between
-    // endpoints are published in case both endpoints are
-    // not connected to the same relay.
-    swarm
-      .behaviour_mut()
-      .subscribe(format!("/{}/message", network_id));
+    // peers banned before a restart stay banned
+    // until their ban runs out.
+    match storage.bans() {
+      Ok(bans) => {
+        for (peer, ban) in bans {
+          if !swarm.behaviour_mut().restore_ban(peer, ban) {
+            if let Err(e) = storage.remove_ban(&peer) {
+              warn!("Failed to remove forgiven ban of {peer}: {e}");
+            }
+          }
+        }
+      }
+      Err(e) => error!("Failed to load peer bans: {e}"),
+    }
+
+    // peers seen in previous runs are candidates for the passive views,
+    // so the node can rejoin the network even if bootstrap nodes are down.
+    match storage.known_peers() {
+      Ok(peers) => {
+        for (peer, record) in peers {
+          if record.age() > ADDRESS_BOOK_MAX_AGE {
+            if let Err(e) = storage.remove_known_peer(&peer) {
+              warn!("Failed to remove stale known peer {peer}: {e}");
+            }
+          } else {
+            swarm.behaviour_mut().add_known_peer(peer, record.addresses);
+          }
+        }
+      }
+      Err(e) => error!("Failed to load known peers: {e}"),
+    }
+
+    let mut router = Router::new(network_id.clone(), keypair.clone(), shards);
+
+    // This is the topic where messages are sent directly to
+    // nodes that host subscribers on the message topic.
+    swarm.behaviour_mut().subscribe(router.direct_topic());
+
+    // Those are the topics where messages are broadcast when
+    // no node has announced subscribers on their topic. Each
+    // node joins only a subset of the shards, messages in those
+    // shards are replicated in its mailbox.
+    for shard in router.shards() {
+      swarm.behaviour_mut().subscribe(router.shard_topic(*shard));
+    }
 
     // This is the topic where all subscription announcements
     // are published so that all nodes are aware that a subscription
@@ -142,24 +311,150 @@ impl Network {
       .behaviour_mut()
       .subscribe(format!("/{}/ack", network_id));
 
+    let mut topics = vec![router.direct_topic()];
+    topics.extend(router.shards().iter().map(|s| router.shard_topic(*s)));
+    topics.push(format!("/{}/subscribe", network_id));
+    topics.push(format!("/{}/ack", network_id));
+
     listenaddrs.for_each(|addr| {
       swarm.listen_on(addr).unwrap();
     });
 
     let (netin_tx, netin_rx) = unbounded_channel();
     let (netout_tx, mut netout_rx) = unbounded_channel();
+    let seeds_tx = netout_tx.clone();
+
+    // Connect to bootstrap nodes on startup. Bootstrap nodes will then
+    // introduce the current node to the rest of the p2p mesh.
+    if !bootstrap.is_empty() {
+      dial_seeds(bootstrap.clone(), seeds_tx.clone());
+    }
 
     tokio::spawn(async move {
+      let mut announce_shards = interval(SHARDS_ANNOUNCE_INTERVAL);
+      let mut announce_subscriptions =
+        interval(SUBSCRIPTIONS_ANNOUNCE_INTERVAL);
+      let mut report_mesh = interval(MESH_REPORT_INTERVAL);
+      let mut save_peers = interval(ADDRESS_BOOK_SAVE_INTERVAL);
+      let mut lookup_seeds = interval_at(
+        Instant::now() + SEED_LOOKUP_INTERVAL,
+        SEED_LOOKUP_INTERVAL,
+      );
       loop {
         tokio::select! {
+          _ = announce_shards.tick() => {
+            let addresses = listen_addresses(&swarm);
+            let topic = format!("/{}/subscribe", network_id);
+            let update = bincode::serialize(&SubscriptionUpdate::Shards {
+              node: local_node,
+              count: router.shard_count(),
+              shards: router.shards().to_vec(),
+              addresses,
+            })
+            .expect("failed to serialize shards info");
+            if let Err(e) = swarm
+            .behaviour_mut()
+            .publish(&topic, Envelope::seal(&keypair, &topic, update).to_bytes()) {
+              debug!("Failed to announce shards: {e}");
+            }
+          },
+          _ = announce_subscriptions.tick() => {
+            router.expire_subscriptions();
+            let addresses = listen_addresses(&swarm);
+            let topic = format!("/{}/subscribe", network_id);
+            let local = router.local_subscriptions();
+            for topics in local.chunks(SUBSCRIPTIONS_PER_ANNOUNCEMENT) {
+              // keeps the local entries from expiring as well
+              for sub in topics {
+                router.subscription_created(*sub, local_node, addresses.clone());
+              }
+              let update = bincode::serialize(&SubscriptionUpdate::Hosted {
+                node: local_node,
+                topics: topics.to_vec(),
+                addresses: addresses.clone(),
+              })
+              .expect("failed to serialize subscriptions info");
+              if let Err(e) = swarm
+              .behaviour_mut()
+              .publish(&topic, Envelope::seal(&keypair, &topic, update).to_bytes()) {
+                debug!("Failed to announce subscriptions: {e}");
+              }
+            }
+          },
+          _ = lookup_seeds.tick() => {
+            // Connect to bootstrap nodes again whenever the node starves
+            // on the topic all nodes are members of. Seeds are resolved on
+            // every attempt, so changes to their DNS records are picked up
+            // without restarting the node.
+            let topic = format!("/{}/subscribe", network_id);
+            if !bootstrap.is_empty() && swarm.behaviour().starved(&topic) {
+              dial_seeds(bootstrap.clone(), seeds_tx.clone());
+            }
+          },
+          _ = save_peers.tick() => {
+            for (peer, addresses) in swarm.behaviour().active_peers() {
+              let record = KnownPeer::seen_now(addresses);
+              if let Err(e) = storage.store_known_peer(&peer, &record) {
+                warn!("Failed to save known peer {peer}: {e}");
+              }
+            }
+          },
+          _ = report_mesh.tick() => {
+            for (peer, score) in swarm.behaviour().peer_scores() {
+              debug!("Peer {peer} score {:.2}: {score:?}", score.value());
+            }
+            for (topic, metrics) in swarm.behaviour().diversity_metrics() {
+              debug!("Subnet diversity rejections on {topic}: {metrics:?}");
+            }
+          },
           Some(event) = swarm.next() => {
-            if let SwarmEvent::Behaviour(EpisubEvent::Message {
+            if let SwarmEvent::Behaviour(EpisubEvent::Subscribed(topic)) = event {
+              debug!("Subscribed to gossip topic {topic}");
+            } else if let SwarmEvent::Behaviour(EpisubEvent::Unsubscribed(topic)) = event {
+              debug!("Unsubscribed from gossip topic {topic}");
+            } else if let SwarmEvent::Behaviour(EpisubEvent::PeerBanned(peer, ban)) = event {
+              if let Err(e) = storage.store_ban(&peer, &ban) {
+                error!("Failed to persist ban of {peer}: {e}");
+              }
+              if let Err(e) = storage.remove_known_peer(&peer) {
+                warn!("Failed to remove banned peer {peer} from address book: {e}");
+              }
+            } else if let SwarmEvent::Behaviour(EpisubEvent::Undelivered {
               topic,
+              peer,
               payload,
-              ..
             }) = event
             {
-              if topic == format!("/{}/message", network_id) {
+              // the node is gone or runs a protocol version without direct
+              // messages, fall back to broadcasting until it announces
+              // itself again.
+              warn!("Peer {peer} is unreachable, rerouting message on {topic}");
+              router.unreachable(&peer.into());
+              router.reroute(&mut swarm, &topic, payload.to_vec());
+            } else if let SwarmEvent::Behaviour(EpisubEvent::Message {
+              topic,
+              payload,
+              id,
+            }) = event
+            {
+              debug!("Received gossip message {id} on topic {topic}");
+              let domain = match router.is_message_topic(&topic) {
+                true => router.message_domain(),
+                false => topic.clone(),
+              };
+
+              // everything relay nodes gossip is signed by the node that
+              // created it, anything that fails verification is dropped.
+              let envelope = match Envelope::open(&payload, &domain) {
+                Ok(envelope) => envelope,
+                Err(e) => {
+                  warn!("Rejected gossip message {id} on topic {topic}: {e}");
+                  continue;
+                }
+              };
+              let payload = envelope.payload;
+
+              if router.is_message_topic(&topic) {
                 match bincode::deserialize(&payload) {
                   Ok(msg) => {
                     netin_tx.send(NetworkEvent::MessageReceived(msg)).unwrap();
@@ -167,19 +462,50 @@ impl Network {
                   Err(e) => error!("Failed to deserialize incoming message: {e}"),
                 }
               } else if topic == format!("/{}/subscribe", network_id) {
-                match bincode::deserialize(&payload) {
-                  Ok(subscription) => {
-                    debug!("Updating subscription {subscription:?}");
-                    netin_tx.send(NetworkEvent::SubscriptionReceived(subscription)).unwrap();
+                match bincode::deserialize::<SubscriptionUpdate>(&payload) {
+                  Ok(update) => {
+                    debug!("Updating subscription {update:?}");
+                    if update.node() != &envelope.origin {
+                      warn!(
+                        "Rejected subscription update {update:?} signed by {}",
+                        envelope.origin
+                      );
+                      continue;
+                    }
+                    match update {
+                      SubscriptionUpdate::Created { topic, node, addresses } => {
+                        router.subscription_created(topic, node, addresses);
+                        netin_tx.send(NetworkEvent::SubscriptionReceived(topic, node)).unwrap();
+                      }
+                      SubscriptionUpdate::Dropped { topic, node } => {
+                        router.subscription_dropped(&topic, &node);
+                        netin_tx.send(NetworkEvent::SubscriptionDropped(topic, node)).unwrap();
+                      }
+                      SubscriptionUpdate::Hosted { node, topics, addresses } => {
+                        for topic in topics {
+                          router.subscription_created(topic, node, addresses.clone());
+                        }
+                      }
+                      SubscriptionUpdate::Shards { node, count, shards, addresses } => {
+                        router.shards_announced(node, count, shards, addresses);
+                      }
+                    }
                   }
                   Err(e) => error!("Failed to deserialize subscription command: {e}"),
                 }
               } else if topic == format!("/{}/ack", network_id) {
-                match Multihash::from_bytes(&payload) {
-                  Ok(msg_hash) => {
-                    netin_tx.send(NetworkEvent::MessageAcknowledged(msg_hash)).unwrap();
+                match bincode::deserialize::<Acknowledgement>(&payload) {
+                  Ok(ack) => {
+                    // acks are not checked against announced subscriptions,
+                    // those travel on another topic and may arrive after
+                    // the ack. The envelope already tells who acked it.
+                    debug!(
+                      "Message {:?} on {:?} acknowledged by {}",
+                      ack.hash, ack.topic, envelope.origin
+                    );
+                    netin_tx.send(NetworkEvent::MessageAcknowledged(ack.hash)).unwrap();
                   }
-                  Err(e) => error!("Failed to deserialize message hash: {e}"),
+                  Err(e) => error!("Failed to deserialize ack: {e}"),
                 }
               } else {
                 warn!("Received a message on an unexpected topic {topic}");
@@ -189,33 +515,72 @@ impl Network {
           Some(event) = netout_rx.recv() => {
             match event {
               NetworkCommand::Connect(addr)=>{
-                if let Err(e) = swarm.dial(addr.clone()) {
+                if swarm.behaviour().connected_at(&addr) {
+                  debug!("Already connected to {addr}");
+                } else if let Err(e) = swarm.dial(addr.clone()) {
                   error!("Dialing peer {addr} failed: {e}");
                 }
               }
-              NetworkCommand::GossipACK(msghash) => {
-                swarm
+              NetworkCommand::GossipACK(sub, msghash) => {
+                let topic = format!("/{}/ack", network_id);
+                let ack = bincode::serialize(&Acknowledgement {
+                  topic: sub,
+                  hash: msghash,
+                })
+                .expect("failed to serialize ack");
+                let ack = Envelope::seal(&keypair, &topic, ack);
+                if let Err(e) = swarm
                 .behaviour_mut()
-                .publish(
-                  &format!("/{}/ack", network_id),
-                  bincode::serialize(&msghash).expect("failed to serialize message"))
-                .unwrap();
+                .publish(&topic, ack.to_bytes()) {
+                  error!("Failed to gossip ack for {msghash:?}: {e}");
+                }
               }
               NetworkCommand::GossipMessage(msg) => {
-                swarm
-                .behaviour_mut()
-                .publish(
-                  &format!("/{}/message", network_id),
-                  bincode::serialize(&msg).expect("failed to serialize message"))
-                .unwrap();
+                router.route(&mut swarm, &msg);
+              }
+              NetworkCommand::Unban(peer) => {
+                swarm.behaviour_mut().unban_peer(&peer);
+                if let Err(e) = storage.remove_ban(&peer) {
+                  error!("Failed to remove ban of {peer}: {e}");
+                }
+              }
+              NetworkCommand::Scores(reply) => {
+                let _ = reply.send(swarm.behaviour().peer_scores());
+              }
+              NetworkCommand::Unsubscribe(topic) => {
+                if swarm.behaviour_mut().unsubscribe(&topic) {
+                  router.topic_left(&topic);
+                }
               }
               NetworkCommand::GossipSubscription(sub) => {
-                swarm
+                let addresses = listen_addresses(&swarm);
+                router.subscription_created(sub, local_node, addresses.clone());
+                let topic = format!("/{}/subscribe", network_id);
+                let update = bincode::serialize(&SubscriptionUpdate::Created {
+                  topic: sub,
+                  node: local_node,
+                  addresses,
+                })
+                .expect("failed to serialize subscription info");
+                if let Err(e) = swarm
                 .behaviour_mut()
-                .publish(
-                  &format!("/{}/subscribe", network_id),
-                  bincode::serialize(&sub).expect("failed to serialize subscription info"))
-                .unwrap();
+                .publish(&topic, Envelope::seal(&keypair, &topic, update).to_bytes()) {
+                  error!("Failed to gossip subscription {sub:?}: {e}");
+                }
+              }
+              NetworkCommand::GossipUnsubscription(sub) => {
+                router.subscription_dropped(&sub, &local_node);
+                let topic = format!("/{}/subscribe", network_id);
+                let update = bincode::serialize(&SubscriptionUpdate::Dropped {
+                  topic: sub,
+                  node: local_node,
+                })
+                .expect("failed to serialize subscription info");
+                if let Err(e) = swarm
+                .behaviour_mut()
+                .publish(&topic, Envelope::seal(&keypair, &topic, update).to_bytes()) {
+                  error!("Failed to gossip unsubscription {sub:?}: {e}");
+                }
               }
             }
           }
@@ -223,16 +588,10 @@ impl Network {
       }
     });
 
-    // Connect to all known bootstrap nodes.
-    // Bootstrap nodes will then introduce the current node
-    // to the rest of the p2p mesh.
-    for addr in bootstrap {
-      netout_tx.send(NetworkCommand::Connect(addr)).unwrap();
-    }
-
     Ok(Self {
       netin: netin_rx,
       netout: netout_tx,
+      topics,
     })
   }
 
@@ -250,11 +609,53 @@ impl Network {
     self.netout.send(NetworkCommand::GossipSubscription(sub))
   }
 
+  pub fn gossip_unsubscription(
+    &mut self,
+    sub: Subscription,
+  ) -> Result<(), SendError<NetworkCommand>> {
+    self.netout.send(NetworkCommand::GossipUnsubscription(sub))
+  }
+
+  /// Leaves a gossip topic, peers on the topic are told that this node
+  /// is gone and connections no other topic uses are closed.
+  pub fn unsubscribe(
+    &mut self,
+    topic: String,
+  ) -> Result<(), SendError<NetworkCommand>> {
+    self.netout.send(NetworkCommand::Unsubscribe(topic))
+  }
+
+  /// Leaves all gossip topics, so peers drop this node from their views
+  /// right away instead of waiting for its connections to time out.
+  pub fn leave(&mut self) -> Result<(), SendError<NetworkCommand>> {
+    for topic in std::mem::take(&mut self.topics) {
+      self.unsubscribe(topic)?;
+    }
+    Ok(())
+  }
+
+  /// Lifts a ban on a peer and forgets its previous offences.
+  pub fn unban_peer(
+    &mut self,
+    peer: PeerId,
+  ) -> Result<(), SendError<NetworkCommand>> {
+    self.netout.send(NetworkCommand::Unban(peer))
+  }
+
+  /// Sends the current scores of all peers known to this node to `reply`.
+  pub fn peer_scores(
+    &mut self,
+    reply: oneshot::Sender<Vec<(PeerId, PeerScore)>>,
+  ) -> Result<(), SendError<NetworkCommand>> {
+    self.netout.send(NetworkCommand::Scores(reply))
+  }
+
   pub fn gossip_ack(
     &mut self,
+    topic: Subscription,
     hash: Multihash,
   ) -> Result<(), SendError<NetworkCommand>> {
-    self.netout.send(NetworkCommand::GossipACK(hash))
+    self.netout.send(NetworkCommand::GossipACK(topic, hash))
   }
 
   pub async fn poll(&mut self) -> Option<NetworkEvent> {
@@ -262,20 +663,48 @@ impl Network {
   }
 }
 
+/// Resolves bootstrap seeds in the background and has the
+/// networking task dial every address they resolve to.
+fn dial_seeds(seeds: Vec<Seed>, commands: UnboundedSender<NetworkCommand>) {
+  tokio::spawn(async move {
+    for addr in seeds::resolve(&seeds).await {
+      if commands.send(NetworkCommand::Connect(addr)).is_err() {
+        break;
+      }
+    }
+  });
+}
+
+/// Addresses other nodes can reach this node on.
+fn listen_addresses(swarm: &Swarm<Episub>) -> Vec<Multiaddr> {
+  swarm
+    .listeners()
+    .filter(|addr| !is_local_address(addr))
+    .cloned()
+    .collect()
+}
+
 // thos modules's role in the main event loop.
 macro_rules! handle {
   ($event:ident, $bus: ident) => {
     match $event {
       NetworkEvent::MessageReceived(msg) => {
         info!("received message {msg:?}");
-        // bus.send_message(msg).await?;
+        if let Err(e) = $bus.send_message(msg) {
+          warn!("Failed to route message to the bus: {e}");
+        }
       }
       NetworkEvent::MessageAcknowledged(hash) => {
         info!("received ack for {hash:?}");
-        $bus.drop_message(&hash); // no need to retry it any longer
+        if let Err(e) = $bus.drop_message(&hash) {
+          warn!("Failed to drop acknowledged message {hash:?}: {e}");
+        }
+      }
+      NetworkEvent::SubscriptionReceived(sub, node) => {
+        info!("received subscription {sub:?} on {node}");
       }
-      NetworkEvent::SubscriptionReceived(sub) => {
-        info!("received subscription {sub:?}");
+      NetworkEvent::SubscriptionDropped(sub, node) => {
+        info!("received unsubscription {sub:?} on {node}");
       }
     }
   };
@@ -0,0 +1,446 @@
+use {
+  super::{envelope::Envelope, episub::Episub},
+  crate::primitives::{Addressable, Keypair, Message, Pubkey, Subscription},
+  libp2p::{Multiaddr, PeerId, Swarm},
+  rand::seq::IteratorRandom,
+  sha3::{Digest, Sha3_256},
+  std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+  },
+  tracing::{debug, error, warn},
+};
+
+/// Decides how messages travel between relay nodes.
+///
+/// A message goes straight to the nodes hosting subscribers on its topic.
+/// When no node has announced the topic, the message is broadcast to the
+/// members of its shard, so it waits in their mailboxes until a recipient
+/// shows up.
+pub struct Router {
+  network_id: String,
+  keypair: Keypair,
+  local_node: Pubkey,
+  shards: ShardConfig,
+  subscriptions: SubscriptionTable,
+  members: ShardTable,
+}
+
+impl Router {
+  pub fn new(
+    network_id: String,
+    keypair: Keypair,
+    shards: ShardConfig,
+  ) -> Self {
+    Self {
+      network_id,
+      local_node: keypair.public(),
+      keypair,
+      shards,
+      subscriptions: SubscriptionTable::new(SUBSCRIPTION_TTL),
+      members: ShardTable::default(),
+    }
+  }
+
+  /// The topic that all nodes are members of, used for sending
+  /// messages directly to nodes that host their recipients.
+  pub fn direct_topic(&self) -> String {
+    format!("/{}/message", self.network_id)
+  }
+
+  /// The topic that messages in a shard are broadcast on.
+  pub fn shard_topic(&self, shard: u16) -> String {
+    format!("/{}/message/{}", self.network_id, shard)
+  }
+
+  /// Shards this node is a member of.
+  pub fn shards(&self) -> &[u16] {
+    &self.shards.members
+  }
+
+  /// Total number of shards in the network.
+  pub fn shard_count(&self) -> u16 {
+    self.shards.count
+  }
+
+  /// Envelopes of relayed messages are signed for the direct topic,
+  /// regardless of the shard topic they end up being broadcast on.
+  pub fn message_domain(&self) -> String {
+    self.direct_topic()
+  }
+
+  /// Tells whether messages on a gossip topic carry relayed messages.
+  pub fn is_message_topic(&self, topic: &str) -> bool {
+    topic == self.direct_topic() || self.shard(topic).is_some()
+  }
+
+  pub fn subscription_created(
+    &mut self,
+    topic: Subscription,
+    node: Pubkey,
+    addresses: Vec<Multiaddr>,
+  ) {
+    self.subscriptions.insert(topic, node, addresses);
+  }
+
+  pub fn subscription_dropped(&mut self, topic: &Subscription, node: &Pubkey) {
+    self.subscriptions.remove(topic, node);
+  }
+
+  /// Topics that have subscribers on this node, they are announced
+  /// periodically so that other nodes keep routing them here.
+  pub fn local_subscriptions(&self) -> Vec<Subscription> {
+    self.subscriptions.topics_of(&self.local_node)
+  }
+
+  /// Forgets subscriptions that were not announced again in time,
+  /// their nodes are most likely gone without saying goodbye.
+  pub fn expire_subscriptions(&mut self) {
+    self.subscriptions.remove_expired();
+  }
+
+  /// Records the shards a node is a member of. Nodes that split the
+  /// network into a different number of shards compute different shards
+  /// for the same topic, so messages are never handed over to them.
+  pub fn shards_announced(
+    &mut self,
+    node: Pubkey,
+    count: u16,
+    shards: Vec<u16>,
+    addresses: Vec<Multiaddr>,
+  ) {
+    if count != self.shards.count {
+      warn!(
+        "Ignoring shards of node {node}, it uses {count} shards instead of {}",
+        self.shards.count
+      );
+      return;
+    }
+    self.members.insert(node, shards, addresses);
+  }
+
+  /// Stops replicating a shard after its topic was left.
+  pub fn topic_left(&mut self, topic: &str) {
+    if let Some(shard) = self.shard(topic) {
+      self.shards.members.retain(|s| *s != shard);
+    }
+  }
+
+  /// Forgets everything known about a node that could not be reached,
+  /// until it announces itself again.
+  pub fn unreachable(&mut self, node: &Pubkey) {
+    self.subscriptions.remove_node(node);
+    self.members.remove_node(node);
+  }
+
+  /// Sends a message straight to the nodes that host subscribers on its
+  /// topic, or broadcasts it to its shard if no node has announced it.
+  pub fn route(&self, swarm: &mut Swarm<Episub>, msg: &Message) {
+    let id = gossip_id(msg);
+    let payload = Envelope::seal(
+      &self.keypair,
+      &self.message_domain(),
+      bincode::serialize(msg).expect("failed to serialize message"),
+    )
+    .to_bytes();
+    match self.subscriptions.nodes(&msg.topic) {
+      Some(nodes) => {
+        // local subscribers get the message through the bus
+        for (node, addresses) in nodes.filter(|(n, _)| **n != self.local_node) {
+          let peer = match PeerId::try_from(*node) {
+            Ok(peer) => peer,
+            Err(e) => {
+              warn!("Invalid node identity {node}: {e}");
+              continue;
+            }
+          };
+          debug!("Routing message {msg:?} to {node}");
+          if let Err(e) = swarm.behaviour_mut().send_direct(
+            &self.direct_topic(),
+            peer,
+            addresses.to_vec(),
+            id,
+            payload.clone(),
+          ) {
+            error!("Failed to send message {msg:?} to {node}: {e}");
+          }
+        }
+      }
+      None => {
+        self.broadcast(swarm, self.shards.shard_of(&msg.topic), id, payload)
+      }
+    }
+  }
+
+  /// Broadcasts a message that could not be delivered directly to a node.
+  pub fn reroute(
+    &self,
+    swarm: &mut Swarm<Episub>,
+    topic: &str,
+    payload: Vec<u8>,
+  ) {
+    let msg = match Envelope::from_bytes(&payload).and_then(|envelope| {
+      Ok(bincode::deserialize::<Message>(&envelope.payload)?)
+    }) {
+      Ok(msg) => msg,
+      Err(e) => return error!("Failed to deserialize message: {e}"),
+    };
+    let shard = self
+      .shard(topic)
+      .unwrap_or_else(|| self.shards.shard_of(&msg.topic));
+    self.broadcast(swarm, shard, gossip_id(&msg), payload);
+  }
+
+  /// Publishes a message to all members of a shard. If this node is not
+  /// one of them, the message is handed over to a known member that
+  /// broadcasts it on our behalf.
+  fn broadcast(
+    &self,
+    swarm: &mut Swarm<Episub>,
+    shard: u16,
+    id: u64,
+    payload: Vec<u8>,
+  ) {
+    let topic = self.shard_topic(shard);
+    if self.shards.is_member(shard) {
+      if let Err(e) = swarm.behaviour_mut().publish_with_id(&topic, id, payload)
+      {
+        error!("Failed to gossip message on {topic}: {e}");
+      }
+      return;
+    }
+
+    match self.members.random_member(shard) {
+      Some((node, addresses)) => match PeerId::try_from(*node) {
+        Ok(peer) => {
+          debug!("Forwarding message on {topic} to {node}");
+          if let Err(e) = swarm.behaviour_mut().forward(
+            &topic,
+            peer,
+            addresses.to_vec(),
+            id,
+            payload,
+          ) {
+            error!("Failed to forward message on {topic}: {e}");
+          }
+        }
+        Err(e) => warn!("Invalid node identity {node}: {e}"),
+      },
+      None => warn!("No known members of shard {shard}, message not gossiped"),
+    }
+  }
+
+  fn shard(&self, topic: &str) -> Option<u16> {
+    topic
+      .strip_prefix(&self.direct_topic())?
+      .strip_prefix('/')?
+      .parse()
+      .ok()
+  }
+}
+
+/// How long a subscription announcement is valid for. Nodes announce their
+/// topics again well before this runs out, entries of nodes that stopped
+/// doing so are dropped and their messages fall back to shard broadcast.
+const SUBSCRIPTION_TTL: Duration = Duration::from_secs(3 * 60);
+
+/// Episub id of a relayed message. It is derived from the message hash
+/// rather than the signed envelope, so copies of the same message published
+/// by different nodes are collapsed into one by the gossip layer.
+fn gossip_id(msg: &Message) -> u64 {
+  let hash = msg.multihash();
+  u64::from_be_bytes(hash.digest()[..8].try_into().unwrap())
+}
+
+/// Message gossip is partitioned into shards, each shard is a separate
+/// Episub topic with its own mesh. A message is published only to the
+/// shard its topic hashes into.
+#[derive(Debug, Clone)]
+pub struct ShardConfig {
+  /// Total number of shards in the network, must be the same on all
+  /// nodes. Shard announcements with a different count are ignored.
+  pub count: u16,
+
+  /// Shards this node is a member of. Messages in those
+  /// shards are replicated in the mailbox of this node.
+  pub members: Vec<u16>,
+}
+
+impl ShardConfig {
+  /// The shard that messages on a topic are published to.
+  pub fn shard_of(&self, topic: &Subscription) -> u16 {
+    let digest = Sha3_256::digest(topic.to_bytes());
+    let value = u64::from_be_bytes(digest[..8].try_into().unwrap());
+    (value % self.count as u64) as u16
+  }
+
+  pub fn is_member(&self, shard: u16) -> bool {
+    self.members.contains(&shard)
+  }
+}
+
+/// Nodes known to be members of message shards, as announced on the
+/// subscriptions topic. Used to hand messages over to a shard that this
+/// node is not a member of.
+#[derive(Debug, Default)]
+struct ShardTable {
+  shards: HashMap<u16, HashMap<Pubkey, Vec<Multiaddr>>>,
+}
+
+impl ShardTable {
+  /// Replaces the known shard membership of a node.
+  pub fn insert(
+    &mut self,
+    node: Pubkey,
+    shards: Vec<u16>,
+    addresses: Vec<Multiaddr>,
+  ) {
+    self.remove_node(&node);
+    for shard in shards {
+      self
+        .shards
+        .entry(shard)
+        .or_default()
+        .insert(node, addresses.clone());
+    }
+  }
+
+  /// Removes a node from all shards, this happens
+  /// when the node could not be reached.
+  pub fn remove_node(&mut self, node: &Pubkey) {
+    self.shards.retain(|_, nodes| {
+      nodes.remove(node);
+      !nodes.is_empty()
+    });
+  }
+
+  /// Picks a random member of a shard.
+  pub fn random_member(&self, shard: u16) -> Option<(&Pubkey, &[Multiaddr])> {
+    self
+      .shards
+      .get(&shard)?
+      .iter()
+      .choose(&mut rand::thread_rng())
+      .map(|(node, addresses)| (node, addresses.as_slice()))
+  }
+}
+
+/// A distributed view of which relay nodes host subscribers on a topic.
+///
+/// The table is built from subscription announcements gossiped by all
+/// nodes, and it lets messages go straight to the nodes that host their
+/// recipients instead of being broadcast to the whole network.
+///
+/// Entries are soft state, they are valid only for a limited time unless
+/// the hosting node announces them again. This way nodes that crashed or
+/// restarted stop receiving messages for topics they no longer host, and
+/// nodes that joined late learn about topics announced before they came.
+#[derive(Debug)]
+struct SubscriptionTable {
+  ttl: Duration,
+  topics: HashMap<Subscription, HashMap<Pubkey, Instant>>,
+  addresses: HashMap<Pubkey, Vec<Multiaddr>>,
+}
+
+impl SubscriptionTable {
+  pub fn new(ttl: Duration) -> Self {
+    Self {
+      ttl,
+      topics: HashMap::new(),
+      addresses: HashMap::new(),
+    }
+  }
+
+  /// Records that a node is hosting subscribers on a topic,
+  /// or extends the validity of an existing entry.
+  pub fn insert(
+    &mut self,
+    topic: Subscription,
+    node: Pubkey,
+    addresses: Vec<Multiaddr>,
+  ) {
+    let expires_at = Instant::now() + self.ttl;
+    self.topics.entry(topic).or_default().insert(node, expires_at);
+    self.addresses.insert(node, addresses);
+  }
+
+  /// Records that the last subscriber on a topic has left a node.
+  pub fn remove(&mut self, topic: &Subscription, node: &Pubkey) {
+    if let Some(nodes) = self.topics.get_mut(topic) {
+      nodes.remove(node);
+      if nodes.is_empty() {
+        self.topics.remove(topic);
+      }
+    }
+    self.forget_if_unused(node);
+  }
+
+  /// Removes a node from all topics, this happens
+  /// when the node could not be reached.
+  pub fn remove_node(&mut self, node: &Pubkey) {
+    self.topics.retain(|_, nodes| {
+      nodes.remove(node);
+      !nodes.is_empty()
+    });
+    self.addresses.remove(node);
+  }
+
+  /// Removes all entries that were not refreshed before their expiry.
+  pub fn remove_expired(&mut self) {
+    let now = Instant::now();
+    self.topics.retain(|_, nodes| {
+      nodes.retain(|_, expires_at| *expires_at > now);
+      !nodes.is_empty()
+    });
+
+    let topics = &self.topics;
+    self
+      .addresses
+      .retain(|node, _| topics.values().any(|nodes| nodes.contains_key(node)));
+  }
+
+  /// Topics a node has announced subscribers on.
+  pub fn topics_of(&self, node: &Pubkey) -> Vec<Subscription> {
+    self
+      .topics
+      .iter()
+      .filter(|(_, nodes)| nodes.contains_key(node))
+      .map(|(topic, _)| *topic)
+      .collect()
+  }
+
+  /// Lists the nodes hosting subscribers on a topic along with their
+  /// addresses, or None if no node has a valid entry for the topic.
+  pub fn nodes(
+    &self,
+    topic: &Subscription,
+  ) -> Option<impl Iterator<Item = (&Pubkey, &[Multiaddr])>> {
+    let now = Instant::now();
+    let nodes = self.topics.get(topic)?;
+    if nodes.values().all(|expires_at| *expires_at <= now) {
+      return None;
+    }
+
+    Some(
+      nodes
+        .iter()
+        .filter(move |(_, expires_at)| **expires_at > now)
+        .map(|(node, _)| {
+          (
+            node,
+            self
+              .addresses
+              .get(node)
+              .map(Vec::as_slice)
+              .unwrap_or_default(),
+          )
+        }),
+    )
+  }
+
+  fn forget_if_unused(&mut self, node: &Pubkey) {
+    if !self.topics.values().any(|nodes| nodes.contains_key(node)) {
+      self.addresses.remove(node);
+    }
+  }
+}
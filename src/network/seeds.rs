@@ -0,0 +1,194 @@
+use {
+  libp2p::{
+    multiaddr::{self, Protocol},
+    Multiaddr,
+  },
+  std::{
+    collections::HashSet,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    num::ParseIntError,
+    str::FromStr,
+  },
+  thiserror::Error,
+  tokio::net::lookup_host,
+  tracing::{debug, warn},
+};
+
+/// Port of seeds given as a hostname or an IP address without a port.
+const DEFAULT_PORT: u16 = 44668;
+
+#[derive(Debug, Error)]
+pub enum SeedError {
+  #[error("Invalid seed multiaddress: {0}")]
+  Multiaddr(#[from] multiaddr::Error),
+
+  #[error("Invalid seed port: {0}")]
+  Port(#[from] ParseIntError),
+}
+
+/// A bootstrap node used to join the p2p network.
+///
+/// Seeds are either literal addresses or DNS names. A DNS seed stands
+/// for all the addresses its name resolves to, so bootstrap nodes can
+/// be rotated by updating DNS records instead of redeploying relays.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Seed(Multiaddr);
+
+impl Seed {
+  /// Addresses to dial for this seed. DNS names are expanded into
+  /// all of their A and AAAA records, or only one of those record types
+  /// for `/dns4` and `/dns6` addresses.
+  pub async fn resolve(&self) -> std::io::Result<Vec<Multiaddr>> {
+    let mut protocols = self.0.iter();
+    let (host, ipv4, ipv6) = match protocols.next() {
+      Some(Protocol::Dns(host)) => (host, true, true),
+      Some(Protocol::Dns4(host)) => (host, true, false),
+      Some(Protocol::Dns6(host)) => (host, false, true),
+      _ => return Ok(vec![self.0.clone()]),
+    };
+
+    // everything after the hostname, usually the tcp port
+    let rest: Vec<_> = protocols.collect();
+    let ips: HashSet<IpAddr> = lookup_host((host.as_ref(), 0))
+      .await?
+      .map(|addr| addr.ip())
+      .filter(|ip| (ip.is_ipv4() && ipv4) || (ip.is_ipv6() && ipv6))
+      .collect();
+
+    Ok(
+      ips
+        .into_iter()
+        .map(|ip| {
+          let mut addr = Multiaddr::from(ip);
+          rest.iter().cloned().for_each(|p| addr.push(p));
+          addr
+        })
+        .collect(),
+    )
+  }
+}
+
+/// Resolves all seeds into addresses to dial. Seeds that fail
+/// to resolve are skipped, they may resolve on the next attempt.
+pub async fn resolve(seeds: &[Seed]) -> Vec<Multiaddr> {
+  let mut addresses = vec![];
+  for seed in seeds {
+    match seed.resolve().await {
+      Ok(resolved) => {
+        debug!("Seed {seed} resolved to {resolved:?}");
+        addresses.extend(resolved);
+      }
+      Err(e) => warn!("Failed to resolve seed {seed}: {e}"),
+    }
+  }
+  addresses
+}
+
+/// Parses seeds given as multiaddresses, e.g. `/dns4/seed.example/tcp/44668`,
+/// socket addresses, or hostnames with an optional port.
+impl FromStr for Seed {
+  type Err = SeedError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if s.starts_with('/') {
+      return Ok(Seed(s.parse()?));
+    }
+
+    let (host, port) = if let Ok(addr) = s.parse::<SocketAddr>() {
+      (Protocol::from(addr.ip()), addr.port())
+    } else if let Ok(ip) = s.parse::<IpAddr>() {
+      (Protocol::from(ip), DEFAULT_PORT)
+    } else if let Some((host, port)) = s.rsplit_once(':') {
+      (Protocol::Dns(host.to_owned().into()), port.parse()?)
+    } else {
+      (Protocol::Dns(s.to_owned().into()), DEFAULT_PORT)
+    };
+
+    let mut addr = Multiaddr::empty();
+    addr.push(host);
+    addr.push(Protocol::Tcp(port));
+    Ok(Seed(addr))
+  }
+}
+
+impl fmt::Display for Seed {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::{Seed, SeedError},
+    libp2p::{multiaddr::Protocol, Multiaddr},
+  };
+
+  fn parse(s: &str) -> String {
+    s.parse::<Seed>().unwrap().to_string()
+  }
+
+  async fn resolve(s: &str) -> Vec<Vec<Protocol<'static>>> {
+    let seed: Seed = s.parse().unwrap();
+    let resolved = seed.resolve().await.unwrap();
+    resolved
+      .iter()
+      .map(Multiaddr::iter)
+      .map(|addr| addr.map(Protocol::acquire).collect())
+      .collect()
+  }
+
+  #[tokio::test]
+  async fn resolves_hostnames_to_addresses_of_the_requested_family() {
+    let ipv4 = resolve("/dns4/localhost/tcp/9000").await;
+    assert!(!ipv4.is_empty());
+    for addr in ipv4 {
+      assert!(matches!(addr[..], [Protocol::Ip4(_), Protocol::Tcp(9000)]));
+    }
+
+    // hosts without ipv6 configured resolve nothing here
+    for addr in resolve("/dns6/localhost/tcp/9000").await {
+      assert!(matches!(addr[..], [Protocol::Ip6(_), Protocol::Tcp(9000)]));
+    }
+  }
+
+  #[test]
+  fn parses_socket_addresses() {
+    assert_eq!(parse("10.0.0.1:9000"), "/ip4/10.0.0.1/tcp/9000");
+    assert_eq!(parse("[::1]:9000"), "/ip6/::1/tcp/9000");
+  }
+
+  #[test]
+  fn parses_bare_ips_with_default_port() {
+    assert_eq!(parse("10.0.0.1"), "/ip4/10.0.0.1/tcp/44668");
+    assert_eq!(parse("::1"), "/ip6/::1/tcp/44668");
+  }
+
+  #[test]
+  fn parses_hostnames() {
+    assert_eq!(parse("seed.example:9000"), "/dns/seed.example/tcp/9000");
+    assert_eq!(parse("seed.example"), "/dns/seed.example/tcp/44668");
+  }
+
+  #[test]
+  fn parses_multiaddresses() {
+    assert_eq!(
+      parse("/dns4/seed.example/tcp/44668"),
+      "/dns4/seed.example/tcp/44668"
+    );
+    assert_eq!(parse("/ip4/10.0.0.1/tcp/9000"), "/ip4/10.0.0.1/tcp/9000");
+  }
+
+  #[test]
+  fn rejects_invalid_seeds() {
+    assert!(matches!(
+      "seed.example:port".parse::<Seed>(),
+      Err(SeedError::Port(_))
+    ));
+    assert!(matches!(
+      "/dns4".parse::<Seed>(),
+      Err(SeedError::Multiaddr(_))
+    ));
+  }
+}
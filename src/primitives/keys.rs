@@ -77,6 +77,19 @@ impl From<libp2p::PeerId> for Pubkey {
   }
 }
 
+impl TryFrom<Pubkey> for libp2p::PeerId {
+  type Error = libp2p::identity::error::DecodingError;
+
+  fn try_from(p: Pubkey) -> Result<Self, Self::Error> {
+    Ok(
+      libp2p::identity::PublicKey::Ed25519(
+        libp2p::identity::ed25519::PublicKey::decode(&p.0)?,
+      )
+      .to_peer_id(),
+    )
+  }
+}
+
 impl PartialEq<libp2p::PeerId> for Pubkey {
   fn eq(&self, other: &libp2p::PeerId) -> bool {
     self.0.eq(&other.as_ref().digest()[4..])
@@ -13,18 +13,37 @@ use {
 pub struct Message {
   pub topic: Multihash,
   pub content: Vec<u8>,
+
+  /// Number of seconds this message is kept in relay mailboxes
+  /// while there is no subscriber on its topic.
+  pub ttl: u32,
+
   #[serde(skip)]
   hashcache: OnceCell<Multihash>,
 }
 
 impl Message {
+  /// TTL used for messages that don't specify one, 5 minutes.
+  pub const DEFAULT_TTL: u32 = 300;
+  /// Upper bound on how long a message can stay in a mailbox, 30 days.
+  pub const MAX_TTL: u32 = 30 * 24 * 60 * 60;
+
   pub fn new(topic: Multihash, content: Vec<u8>) -> Self {
     Self {
       topic,
       content,
+      ttl: Self::DEFAULT_TTL,
       hashcache: OnceCell::new(),
     }
   }
+
+  /// Sets the mailbox TTL of the message, capped at [`Self::MAX_TTL`].
+  pub fn with_ttl(self, ttl: u32) -> Self {
+    Self {
+      ttl: ttl.min(Self::MAX_TTL),
+      ..self
+    }
+  }
 }
 
 /// This is the unique identifier of a message.
@@ -37,7 +56,7 @@ impl Addressable for Message {
   fn multihash(&self) -> Multihash {
     *self.hashcache.get_or_init(|| {
       let mut hasher = Sha3_256::new();
-      hasher.update(&self.topic.to_bytes());
+      hasher.update(self.topic.to_bytes());
       hasher.update(&self.content);
       multihash::Code::Sha3_256.wrap(&hasher.finalize()).unwrap()
     })
@@ -49,6 +68,7 @@ impl Debug for Message {
     f.debug_struct("Message")
       .field("topic", &self.topic)
       .field("content", &self.content)
+      .field("ttl", &self.ttl)
       .field("hash", &self.multihash())
       .finish()
   }
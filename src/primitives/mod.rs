@@ -3,8 +3,14 @@ use multihash::Multihash;
 mod keys;
 mod message;
 mod subscription;
+mod time;
 
-pub use {keys::*, message::Message, subscription::Subscription};
+pub use {
+  keys::*,
+  message::Message,
+  subscription::Subscription,
+  time::unix_time,
+};
 
 pub trait Addressable {
   fn multihash(&self) -> Multihash;
@@ -0,0 +1,8 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Time elapsed since unix epoch according to the system clock.
+pub fn unix_time() -> Duration {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("system clock is set before unix epoch")
+}
@@ -1,15 +1,38 @@
 mod protocol;
 mod service;
-pub use service::RpcService;
+mod session;
 use {
-  crate::primitives::{Message, Subscription},
-  axum::extract::ws::WebSocket,
+  crate::{
+    bus::SendError,
+    network::PeerScore,
+    primitives::{Message, Subscription},
+  },
+  libp2p::PeerId,
+  multihash::Multihash,
+  tokio::sync::oneshot,
 };
+pub use {
+  service::RpcService,
+  session::{Session, SessionClosed, SessionId, SubscriptionId},
+};
+
+/// Tells the RPC service how the message bus has handled a request,
+/// so the client gets to know if it failed.
+pub type Reply = oneshot::Sender<Result<(), SendError>>;
 
 #[derive(Debug)]
 pub enum RpcEvent {
   Message(Message),
-  _Subscription(Subscription, WebSocket),
+  Subscription(Subscription, SubscriptionId, Session),
+  Unsubscription(SubscriptionId, SessionId, Reply),
+  Acknowledgement(Subscription, Multihash, SessionId, Reply),
+  SessionClosed(SessionId),
+
+  /// An operator lifted the ban on a peer through the admin API.
+  Unban(PeerId),
+
+  /// An operator asked for the scores of peers through the admin API.
+  Scores(oneshot::Sender<Vec<(PeerId, PeerScore)>>),
 }
 
 macro_rules! handle {
@@ -18,11 +41,45 @@ macro_rules! handle {
       RpcEvent::Message(msg) => {
         info!("rpc-event message: {msg:?}");
         $network.gossip_message(msg.clone())?;
-        // bus.send_message(msg).await?;
+
+        // the subscriber might be connected to the same relay
+        if let Err(e) = $bus.send_message(msg) {
+          warn!("Failed to route message to the bus: {e}");
+        }
+      }
+      RpcEvent::Subscription(sub, id, session) => {
+        info!("rpc-event subscription {id}: {sub:?} from {session:?}");
+        if let Err(e) = $bus.create_subscription(sub, id, session) {
+          warn!("Failed to create subscription on {sub:?}: {e}");
+        }
+      }
+      RpcEvent::Unsubscription(id, session, reply) => {
+        info!("rpc-event unsubscription {id} from {session:?}");
+        let result = $bus.drop_session_subscription(id, session);
+        if let Err(e) = &result {
+          warn!("Failed to drop subscription {id}: {e}");
+        }
+        let _ = reply.send(result);
+      }
+      RpcEvent::Acknowledgement(sub, hash, session, reply) => {
+        info!("rpc-event ack {hash:?} on {sub:?} from {session:?}");
+        let result = $bus.acknowledge_message(sub, hash, session);
+        if let Err(e) = &result {
+          warn!("Failed to acknowledge message {hash:?}: {e}");
+        }
+        let _ = reply.send(result);
+      }
+      RpcEvent::SessionClosed(session) => {
+        info!("rpc-event session {session:?} closed");
+        $bus.drop_session(session);
+      }
+      RpcEvent::Unban(peer) => {
+        info!("rpc-event unban {peer}");
+        $network.unban_peer(peer)?;
       }
-      RpcEvent::_Subscription(sub, socket) => {
-        info!("rpc-event subscription: {sub:?}");
-        $bus.create_subscription(sub, socket);
+      RpcEvent::Scores(reply) => {
+        info!("rpc-event scores");
+        $network.peer_scores(reply)?;
       }
     }
   };
@@ -1,40 +1,205 @@
 use {
-  crate::primitives::{Message, Subscription},
-  core::fmt,
-  either::Either,
+  super::session::SubscriptionId,
+  crate::{
+    bus::SendError,
+    network::MAX_MESSAGE_SIZE,
+    primitives::{Addressable, Message, Subscription},
+  },
   multihash::Multihash,
-  serde_json::Value,
-  std::{fmt::Display, str::FromStr},
+  serde_json::{json, Value},
+  std::str::FromStr,
   thiserror::Error,
 };
 
 #[derive(Debug, Error)]
 pub enum RequestError {
+  #[error("Invalid request: {0}")]
+  InvalidRequest(String),
+
+  #[error("Invalid method: {0}")]
   InvalidMethod(String),
+
+  #[error("Missing field: {0}")]
   MissingField(String),
+
+  #[error("Invalid subscription id: {0}")]
+  InvalidSubscription(String),
+
+  #[error("Message is larger than {0} bytes")]
+  MessageTooLarge(usize),
+
+  #[error("Base58 Error: {0}")]
   Base58Error(#[from] bs58::decode::Error),
+
+  #[error("Multihash Error: {0}")]
   MultihashError(#[from] multihash::Error),
+
+  #[error("Deserialization Error: {0}")]
   Deserialization(#[from] serde_json::Error),
+
+  #[error("Not subscribed: {0}")]
+  NotSubscribed(String),
+
+  #[error("Unknown message: {0}")]
+  UnknownMessage(String),
+
+  #[error("Internal error: {0}")]
+  Internal(String),
+}
+
+impl RequestError {
+  /// The JSON-RPC 2.0 error code reported back to the client.
+  ///
+  /// Codes between -32768 and -32000 are reserved by the spec, errors
+  /// that have no predefined meaning use the implementation-defined
+  /// server error range -32000 to -32099.
+  pub fn code(&self) -> i64 {
+    match self {
+      RequestError::Deserialization(_) => -32700,
+      RequestError::InvalidRequest(_) => -32600,
+      RequestError::InvalidMethod(_) => -32601,
+      RequestError::MissingField(_) => -32602,
+      RequestError::InvalidSubscription(_) => -32602,
+      RequestError::MessageTooLarge(_) => -32602,
+      RequestError::Base58Error(_) => -32602,
+      RequestError::MultihashError(_) => -32602,
+      RequestError::Internal(_) => -32603,
+      RequestError::NotSubscribed(_) => -32001,
+      RequestError::UnknownMessage(_) => -32002,
+    }
+  }
+}
+
+/// Requests that were rejected by the message bus.
+impl From<SendError> for RequestError {
+  fn from(error: SendError) -> Self {
+    match error {
+      SendError::NotSubscribed(topic) => {
+        RequestError::NotSubscribed(encode_multihash(&topic))
+      }
+      SendError::UnknownSubscription(id) => {
+        RequestError::NotSubscribed(id.to_string())
+      }
+      SendError::UnknownMessage(hash) => {
+        RequestError::UnknownMessage(encode_multihash(&hash))
+      }
+      e @ (SendError::Session(_) | SendError::Storage(_)) => {
+        RequestError::Internal(e.to_string())
+      }
+    }
+  }
+}
+
+/// A request made by an end-party through the RPC API.
+#[derive(Debug)]
+pub enum Request {
+  /// `irn_publish`: sends a message on a topic.
+  Publish(Message),
+
+  /// `irn_subscribe`: starts receiving messages on a topic.
+  Subscribe(Subscription),
+
+  /// `irn_unsubscribe`: cancels a subscription created through
+  /// `irn_subscribe`, other subscriptions on the same topic stay open.
+  Unsubscribe(SubscriptionId),
+
+  /// `irn_ack`: confirms that a message delivered on a subscribed
+  /// topic was processed and doesn't need to be delivered again.
+  Acknowledge(Subscription, Multihash),
+}
+
+/// A JSON-RPC call made on the socket.
+#[derive(Debug)]
+pub enum Call {
+  /// A single request object.
+  Single(Value),
+
+  /// An array of requests that are handled independently of each other,
+  /// their responses are returned as an array in the same order.
+  Batch(Vec<Value>),
 }
 
-impl Display for RequestError {
-  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    write!(f, "{self:?}")
+pub fn parse_call(json: &str) -> Result<Call, RequestError> {
+  match Value::from_str(json)? {
+    Value::Array(batch) if batch.is_empty() => {
+      Err(RequestError::InvalidRequest("empty batch".into()))
+    }
+    Value::Array(batch) => Ok(Call::Batch(batch)),
+    single => Ok(Call::Single(single)),
   }
 }
 
-pub fn parse_request(
-  request: &str,
-) -> Result<Either<Message, Subscription>, RequestError> {
-  let json = serde_json::Value::from_str(request)?;
-  let method = get_string(&json, "method")?;
+/// The id of a JSON-RPC request that is echoed back in its response.
+/// Requests without an id are notifications and don't get a response.
+pub fn request_id(json: &Value) -> Option<Value> {
+  json.get("id").cloned()
+}
+
+pub fn parse_request(json: Value) -> Result<Request, RequestError> {
+  if json.get("jsonrpc").and_then(|v| v.as_str()) != Some("2.0") {
+    return Err(RequestError::InvalidRequest("jsonrpc".into()));
+  }
+
+  let method = get_string(&json, "method")
+    .map_err(|_| RequestError::InvalidRequest("method".into()))?;
   match method.as_str() {
-    "irn_subscribe" => Ok(Either::Right(parse_subscribe(json)?)),
-    "irn_publish" => Ok(Either::Left(parse_publish(json)?)),
+    "irn_subscribe" => Ok(Request::Subscribe(parse_topic(json)?)),
+    "irn_unsubscribe" => {
+      Ok(Request::Unsubscribe(parse_subscription_id(&json)?))
+    }
+    "irn_publish" => Ok(Request::Publish(parse_publish(json)?)),
+    "irn_ack" => {
+      let hash = parse_hash(&json)?;
+      Ok(Request::Acknowledge(parse_topic(json)?, hash))
+    }
     v => Err(RequestError::InvalidMethod(v.to_string())),
   }
 }
 
+/// A successful response to the request with the given id.
+pub fn success(id: Value, result: Value) -> Value {
+  json!({
+    "jsonrpc": "2.0",
+    "id": id,
+    "result": result,
+  })
+}
+
+/// An error response to the request with the given id.
+pub fn failure(id: Value, error: &RequestError) -> Value {
+  json!({
+    "jsonrpc": "2.0",
+    "id": id,
+    "error": {
+      "code": error.code(),
+      "message": error.to_string(),
+    },
+  })
+}
+
+/// A notification pushed to a subscriber when a message
+/// is published on the topic it has subscribed to.
+pub fn subscription(id: SubscriptionId, message: &Message) -> String {
+  json!({
+    "jsonrpc": "2.0",
+    "method": "irn_subscription",
+    "params": {
+      "id": id.to_string(),
+      "data": {
+        "topic": encode_multihash(&message.topic),
+        "hash": encode_multihash(&message.multihash()),
+        "message": String::from_utf8_lossy(&message.content),
+      },
+    },
+  })
+  .to_string()
+}
+
+/// Multihashes are represented as base58 strings in the API.
+pub fn encode_multihash(hash: &Multihash) -> String {
+  bs58::encode(hash.to_bytes()).into_string()
+}
+
 fn get_string(
   json: &serde_json::Value,
   name: &str,
@@ -45,15 +210,37 @@ fn get_string(
   Err(RequestError::MissingField(name.into()))
 }
 
-fn parse_subscribe(
-  json: serde_json::Value,
-) -> Result<Subscription, RequestError> {
+fn parse_topic(json: serde_json::Value) -> Result<Subscription, RequestError> {
   if let Some(params) = json.get("params") {
     if let Some(topic) = params.get("topic").and_then(|t| t.as_str()) {
-      if let Ok(topic) = bs58::decode(topic).into_vec() {
-        return Ok(Multihash::from_bytes(&topic)?);
-      }
+      return Ok(Multihash::from_bytes(&bs58::decode(topic).into_vec()?)?);
+    }
+    return Err(RequestError::MissingField("params.topic".to_string()));
+  }
+  Err(RequestError::MissingField("params".to_string()))
+}
+
+fn parse_subscription_id(
+  json: &serde_json::Value,
+) -> Result<SubscriptionId, RequestError> {
+  if let Some(params) = json.get("params") {
+    if let Some(id) = params.get("id").and_then(|t| t.as_str()) {
+      return id
+        .parse()
+        .map(SubscriptionId)
+        .map_err(|_| RequestError::InvalidSubscription(id.to_string()));
     }
+    return Err(RequestError::MissingField("params.id".to_string()));
+  }
+  Err(RequestError::MissingField("params".to_string()))
+}
+
+fn parse_hash(json: &serde_json::Value) -> Result<Multihash, RequestError> {
+  if let Some(params) = json.get("params") {
+    if let Some(hash) = params.get("hash").and_then(|t| t.as_str()) {
+      return Ok(Multihash::from_bytes(&bs58::decode(hash).into_vec()?)?);
+    }
+    return Err(RequestError::MissingField("params.hash".to_string()));
   }
   Err(RequestError::MissingField("params".to_string()))
 }
@@ -71,12 +258,24 @@ fn parse_publish(json: serde_json::Value) -> Result<Message, RequestError> {
       .and_then(|t| t.as_str())
       .map(|content| content.as_bytes().to_vec());
 
+    let ttl = params
+      .get("ttl")
+      .and_then(|t| t.as_u64())
+      .map(|t| t.try_into().unwrap_or(u32::MAX))
+      .unwrap_or(Message::DEFAULT_TTL);
+
     if let Some(content) = content {
-      if let Some(Ok(Ok(topic))) = topic {
-        return Ok(Message::new(topic, content));
-      } else {
-        return Err(RequestError::MissingField("params.topic".to_string()));
+      // larger messages would be rejected by the gossip layer, and
+      // would get this node banned by peers with lower limits.
+      if content.len() > MAX_MESSAGE_SIZE {
+        return Err(RequestError::MessageTooLarge(MAX_MESSAGE_SIZE));
       }
+      return match topic {
+        Some(Ok(Ok(topic))) => Ok(Message::new(topic, content).with_ttl(ttl)),
+        Some(Ok(Err(e))) => Err(e.into()),
+        Some(Err(e)) => Err(e.into()),
+        None => Err(RequestError::MissingField("params.topic".to_string())),
+      };
     } else {
       return Err(RequestError::MissingField("params.message".to_string()));
     }
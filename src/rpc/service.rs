@@ -1,66 +1,99 @@
 use {
-  super::RpcEvent,
+  super::{
+    protocol::{self, parse_request, Call, Request, RequestError},
+    session::{Session, SessionId, SubscriptionId},
+    RpcEvent,
+  },
   crate::{
-    primitives::Pubkey,
-    rpc::protocol::parse_request,
+    bus::SendError,
+    primitives::{Addressable, Pubkey},
     storage::PersistentStorage,
   },
   axum::{
-    extract::{ws, ws::WebSocket, WebSocketUpgrade},
+    extract::{ws, ws::WebSocket, Path, WebSocketUpgrade},
+    http::StatusCode,
     response::IntoResponse,
-    routing::get,
+    routing::{delete, get},
     Extension,
     Router,
   },
   axum_extra::response::ErasedJson,
-  either::Either,
-  futures::Stream,
-  serde_json::json,
-  std::{net::SocketAddr, sync::Arc, task::Poll},
-  tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-  tracing::{debug, info, warn},
+  futures::{Stream, StreamExt},
+  libp2p::PeerId,
+  serde_json::{json, Value},
+  std::{
+    net::SocketAddr,
+    sync::{
+      atomic::{AtomicU64, Ordering},
+      Arc,
+    },
+    task::Poll,
+  },
+  tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    oneshot,
+  },
+  tracing::{debug, warn},
 };
 
 struct ServiceSharedState {
   identity: Pubkey,
+  storage: PersistentStorage,
+  next_session: AtomicU64,
+  next_subscription: AtomicU64,
   events_sender: UnboundedSender<RpcEvent>,
-  events_out: UnboundedReceiver<RpcEvent>,
 }
 
 pub struct RpcService {
-  shared_state: Arc<ServiceSharedState>,
+  events_out: UnboundedReceiver<RpcEvent>,
 }
 
 impl RpcService {
+  /// Serves the public JSON-RPC API on all `addrs`, and the admin API on
+  /// the `admin` address if the operator has enabled it.
   pub fn new(
     addrs: Vec<SocketAddr>,
-    _state: PersistentStorage,
+    admin: Option<SocketAddr>,
+    storage: PersistentStorage,
     identity: Pubkey,
   ) -> Self {
     let (events_sender, events_out) = unbounded_channel();
 
     let shared_state = Arc::new(ServiceSharedState {
       identity,
-      events_out,
+      storage,
+      next_session: AtomicU64::new(0),
+      next_subscription: AtomicU64::new(0),
       events_sender,
     });
 
     let svc = Router::new()
       .route("/info", get(serve_info))
       .route("/rpc", get(serve_rpc))
-      .layer(Extension(Arc::clone(&shared_state)));
-
-    addrs.iter().cloned().for_each(|addr| {
-      let svc = svc.clone();
-      tokio::spawn(async move {
-        axum::Server::bind(&addr)
-          .serve(svc.into_make_service())
-          .await
-          .unwrap();
+      .layer(Extension(shared_state.clone()));
+
+    // admin endpoints change the state of the node, they are served on
+    // their own listener that is never exposed beyond the local machine.
+    let admin_svc = Router::new()
+      .route("/bans", get(serve_bans))
+      .route("/bans/:peer", delete(serve_unban))
+      .route("/scores", get(serve_scores))
+      .layer(Extension(shared_state));
+
+    addrs
+      .into_iter()
+      .map(|addr| (addr, svc.clone()))
+      .chain(admin.map(|addr| (addr, admin_svc)))
+      .for_each(|(addr, svc)| {
+        tokio::spawn(async move {
+          axum::Server::bind(&addr)
+            .serve(svc.into_make_service())
+            .await
+            .unwrap();
+        });
       });
-    });
 
-    Self { shared_state }
+    Self { events_out }
   }
 }
 
@@ -74,6 +107,85 @@ async fn serve_info(
   }))
 }
 
+/// Lists peers that are currently banned from the p2p network.
+async fn serve_bans(
+  Extension(state): Extension<Arc<ServiceSharedState>>,
+) -> Result<ErasedJson, StatusCode> {
+  debug!("Serving bans");
+  let bans = state.storage.bans().map_err(|e| {
+    warn!("Failed to load peer bans: {e}");
+    StatusCode::INTERNAL_SERVER_ERROR
+  })?;
+
+  Ok(ErasedJson::pretty(
+    bans
+      .into_iter()
+      .filter(|(_, ban)| ban.is_active())
+      .map(|(peer, ban)| {
+        json!({
+          "peer": peer.to_string(),
+          "reason": ban.reason,
+          "score": ban.score,
+          "since": ban.since,
+          "until": ban.until,
+        })
+      })
+      .collect::<Vec<_>>(),
+  ))
+}
+
+/// Lifts the ban on a peer and forgets its previous offences.
+/// Peers that are not banned are answered with 404.
+async fn serve_unban(
+  Path(peer): Path<String>,
+  Extension(state): Extension<Arc<ServiceSharedState>>,
+) -> Result<ErasedJson, StatusCode> {
+  let peer: PeerId = peer.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+  let bans = state.storage.bans().map_err(|e| {
+    warn!("Failed to load peer bans: {e}");
+    StatusCode::INTERNAL_SERVER_ERROR
+  })?;
+
+  if !bans.iter().any(|(p, ban)| *p == peer && ban.is_active()) {
+    return Err(StatusCode::NOT_FOUND);
+  }
+
+  debug!("Unbanning peer {peer} through admin API");
+  state.events_sender.send(RpcEvent::Unban(peer)).unwrap();
+  Ok(ErasedJson::pretty(json!(true)))
+}
+
+/// Lists the current scores of all peers known to this node,
+/// best scoring peers first.
+async fn serve_scores(
+  Extension(state): Extension<Arc<ServiceSharedState>>,
+) -> Result<ErasedJson, StatusCode> {
+  debug!("Serving peer scores");
+  let (reply, scores) = oneshot::channel();
+  state.events_sender.send(RpcEvent::Scores(reply)).unwrap();
+  let mut scores = scores.await.map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+  scores.sort_by(|(_, a), (_, b)| b.value().total_cmp(&a.value()));
+
+  Ok(ErasedJson::pretty(
+    scores
+      .into_iter()
+      .map(|(peer, score)| {
+        json!({
+          "peer": peer.to_string(),
+          "score": score.value(),
+          "rank": score.rank(),
+          "rtt": score.rtt.map(|rtt| rtt.as_millis() as u64),
+          "uptime": score.uptime().as_secs(),
+          "invalid_messages": score.invalid_messages,
+          "duplicates": score.duplicates,
+          "useless_grafts": score.useless_grafts,
+          "late_ihaves": score.late_ihaves,
+        })
+      })
+      .collect::<Vec<_>>(),
+  ))
+}
+
 async fn serve_rpc(
   ws: WebSocketUpgrade,
   Extension(state): Extension<Arc<ServiceSharedState>>,
@@ -82,45 +194,195 @@ async fn serve_rpc(
   ws.on_upgrade(|socket| serve_rpc_socket(socket, state))
 }
 
-async fn serve_rpc_socket(
-  mut socket: WebSocket,
-  state: Arc<ServiceSharedState>,
+async fn serve_rpc_socket(socket: WebSocket, state: Arc<ServiceSharedState>) {
+  let (sink, mut stream) = socket.split();
+  let session = Session::new(
+    SessionId(state.next_session.fetch_add(1, Ordering::Relaxed)),
+    sink,
+  );
+
+  debug!("Starting websocket session {:?}", session.id());
+  loop {
+    let msg = tokio::select! {
+      msg = stream.next() => match msg {
+        Some(msg) => msg,
+        None => break,
+      },
+      // the write half was closed because the client fell behind,
+      // dropping the read half as well disconnects the client.
+      _ = session.closed() => break,
+    };
+
+    match msg {
+      Ok(ws::Message::Text(msg)) => {
+        serve_request(&msg, &session, &state).await;
+      }
+      Ok(ws::Message::Ping(_) | ws::Message::Pong(_)) => {}
+      Ok(ws::Message::Close(_)) => break,
+      Ok(msg) => warn!("Invalid message format: {msg:?}"),
+      Err(e) => {
+        debug!("websocket session {:?} failed: {e}", session.id());
+        break;
+      }
+    }
+  }
+
+  // the client is gone, remove all its subscriptions
+  debug!("websocket session {:?} ended", session.id());
+  state
+    .events_sender
+    .send(RpcEvent::SessionClosed(session.id()))
+    .unwrap();
+}
+
+/// Handles a JSON-RPC call made on the socket and writes its response,
+/// unless the call consists only of notifications.
+async fn serve_request(
+  request: &str,
+  session: &Session,
+  state: &ServiceSharedState,
 ) {
-  debug!("Starting a websocket");
-  while let Some(msg) = socket.recv().await {
-    if let Ok(ws::Message::Text(msg)) = msg {
-      match parse_request(&msg) {
-        Ok(request) => match request {
-          // some end-party is publishing a new message
-          Either::Left(message) => {
-            debug!("Received {message:?} through WebSocket API");
-            state
-              .events_sender
-              .send(RpcEvent::Message(message))
-              .unwrap();
-          }
-
-          // ome end-party is establishing a subscription on topic
-          // and awaiting incoming messages
-          Either::Right(subscription) => {
-            debug!("Received {subscription:?} through WebSocket API");
-            state
-              .events_sender
-              .send(RpcEvent::_Subscription(subscription, socket))
-              .unwrap();
-            // subscription created, transfer ownership of the underlying
-            // connection socket out of the RPC module into the message bus.
-            break;
-          }
-        },
-        Err(e) => warn!("Invalid request: {e:?}"),
+  let (response, events) = match protocol::parse_call(request) {
+    Ok(Call::Single(request)) => {
+      let (response, event) = serve_call(request, session, state).await;
+      (response, event.into_iter().collect())
+    }
+    Ok(Call::Batch(batch)) => {
+      let mut responses = vec![];
+      let mut events = vec![];
+      for request in batch {
+        let (response, event) = serve_call(request, session, state).await;
+        responses.extend(response);
+        events.extend(event);
+      }
+      let response = (!responses.is_empty()).then_some(Value::Array(responses));
+      (response, events)
+    }
+    Err(e) => {
+      warn!("Invalid request: {e}");
+      (Some(protocol::failure(Value::Null, &e)), vec![])
+    }
+  };
+
+  // the response has to reach the client before any event is processed,
+  // otherwise messages replayed to a new subscription could arrive before
+  // the client learns the subscription id.
+  if let Some(response) = response {
+    let _ = session.send(ws::Message::Text(response.to_string()));
+  }
+  for event in events {
+    state.events_sender.send(event).unwrap();
+  }
+}
+
+/// Handles a single request object, returns its response unless the request
+/// is a notification, and the event it emits once the response is written.
+async fn serve_call(
+  request: Value,
+  session: &Session,
+  state: &ServiceSharedState,
+) -> (Option<Value>, Option<RpcEvent>) {
+  let id = protocol::request_id(&request);
+  match parse_request(request) {
+    Ok(request) => match execute(request, session, state).await {
+      Ok((result, event)) => {
+        (id.map(|id| protocol::success(id, result)), event)
+      }
+      Err(e) => {
+        warn!("Request failed: {e}");
+        (id.map(|id| protocol::failure(id, &e)), None)
       }
-    } else {
-      warn!("Invalid message format: {msg:?}");
+    },
+    Err(e) => {
+      warn!("Invalid request: {e}");
+      // a malformed request object can't be a notification,
+      // so it is always answered, with a null id if it has none.
+      let id = match e {
+        RequestError::InvalidRequest(_) => Some(id.unwrap_or(Value::Null)),
+        _ => id,
+      };
+      (id.map(|id| protocol::failure(id, &e)), None)
     }
   }
 }
 
+/// Turns a valid request into the result that is returned to the client
+/// and the event that is emitted by the RPC service after the response.
+///
+/// Requests that can be rejected by the message bus are handed over to it
+/// right away, and are answered only once the bus has handled them.
+async fn execute(
+  request: Request,
+  session: &Session,
+  state: &ServiceSharedState,
+) -> Result<(Value, Option<RpcEvent>), RequestError> {
+  match request {
+    // some end-party is publishing a new message
+    Request::Publish(message) => {
+      debug!("Received {message:?} through WebSocket API");
+      Ok((
+        json!(protocol::encode_multihash(&message.multihash())),
+        Some(RpcEvent::Message(message)),
+      ))
+    }
+
+    // some end-party is establishing a subscription on topic
+    // and awaiting incoming messages. The session stays open for
+    // further requests, while the message bus delivers messages
+    // through its write half.
+    Request::Subscribe(subscription) => {
+      debug!("Received {subscription:?} through WebSocket API");
+      let id =
+        SubscriptionId(state.next_subscription.fetch_add(1, Ordering::Relaxed));
+      Ok((
+        json!(id.to_string()),
+        Some(RpcEvent::Subscription(subscription, id, session.clone())),
+      ))
+    }
+
+    // some end-party is no longer interested in messages on one
+    // of the subscriptions it has created through this session.
+    Request::Unsubscribe(id) => {
+      debug!("Received unsubscribe {id} through WebSocket API");
+      let (reply, result) = oneshot::channel();
+      state
+        .events_sender
+        .send(RpcEvent::Unsubscription(id, session.id(), reply))
+        .unwrap();
+      await_reply(result).await
+    }
+
+    // some end-party confirms that it has processed a message
+    // delivered on one of its subscriptions.
+    Request::Acknowledge(subscription, hash) => {
+      debug!("Received ack for {hash:?} through WebSocket API");
+      let (reply, result) = oneshot::channel();
+      state
+        .events_sender
+        .send(RpcEvent::Acknowledgement(
+          subscription,
+          hash,
+          session.id(),
+          reply,
+        ))
+        .unwrap();
+      await_reply(result).await
+    }
+  }
+}
+
+/// Waits for the message bus to handle a request, requests it
+/// accepted are answered with `true`.
+async fn await_reply(
+  result: oneshot::Receiver<Result<(), SendError>>,
+) -> Result<(Value, Option<RpcEvent>), RequestError> {
+  match result.await {
+    Ok(Ok(())) => Ok((json!(true), None)),
+    Ok(Err(e)) => Err(e.into()),
+    Err(_) => Err(RequestError::Internal("message bus is gone".into())),
+  }
+}
+
 impl Unpin for RpcService {}
 impl Stream for RpcService {
   type Item = RpcEvent;
@@ -129,15 +391,9 @@ impl Stream for RpcService {
     mut self: std::pin::Pin<&mut Self>,
     cx: &mut std::task::Context<'_>,
   ) -> std::task::Poll<Option<Self::Item>> {
-    if let Some(shared_state) = Arc::get_mut(&mut self.shared_state) {
-      if let Poll::Ready(Some(event)) = shared_state.events_out.poll_recv(cx) {
-        info!("popping an event from RPC Service: {event:?}");
-        return Poll::Ready(Some(event));
-      } else {
-        warn!("Nothing in RPC events queue");
-      }
-    } else {
-      warn!("Can't get mut arc");
+    if let Poll::Ready(Some(event)) = self.events_out.poll_recv(cx) {
+      debug!("popping an event from RPC Service: {event:?}");
+      return Poll::Ready(Some(event));
     }
     Poll::Pending
   }
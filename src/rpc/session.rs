@@ -0,0 +1,138 @@
+use {
+  super::protocol,
+  crate::primitives,
+  axum::extract::ws::{Message, WebSocket},
+  futures::{stream::SplitSink, SinkExt},
+  std::{
+    fmt::{Debug, Display},
+    sync::Arc,
+  },
+  thiserror::Error,
+  tokio::sync::{
+    mpsc::{channel, error::TrySendError, Sender},
+    Notify,
+  },
+  tracing::{debug, warn},
+};
+
+/// Maximum number of messages waiting to be written to a client socket.
+/// A client that doesn't read its socket fast enough to stay below this
+/// limit has its session closed, instead of making the relay buffer an
+/// ever growing backlog of messages for it.
+const MAX_PENDING_MESSAGES: usize = 1024;
+
+/// Uniquely identifies a client connection to the RPC service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SessionId(pub(super) u64);
+
+/// Uniquely identifies a subscription created through `irn_subscribe`.
+///
+/// The same topic may have many subscriptions at once, for example when a
+/// wallet has the same pairing open on several devices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(pub(super) u64);
+
+impl Display for SubscriptionId {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+#[derive(Debug, Error)]
+#[error("Session {0:?} is closed")]
+pub struct SessionClosed(pub SessionId);
+
+/// The write half of a client WebSocket connection.
+///
+/// A client keeps one long-lived connection with the relay, and uses it
+/// to publish messages and to manage any number of subscriptions. While
+/// the RPC service keeps reading requests from the socket, the write half
+/// is shared with the message bus that delivers messages on all topics
+/// subscribed through this session.
+///
+/// Cloning a session is cheap, all clones write to the same socket.
+#[derive(Clone)]
+pub struct Session {
+  id: SessionId,
+  outbound: Sender<Message>,
+
+  /// Signals the writer task to give up on a client that fell behind.
+  overflow: Arc<Notify>,
+}
+
+impl Session {
+  /// Starts a task that owns the write half of the socket and
+  /// forwards to it everything that is sent through the session.
+  pub(super) fn new(
+    id: SessionId,
+    mut sink: SplitSink<WebSocket, Message>,
+  ) -> Self {
+    let (outbound, mut outbound_rx) = channel(MAX_PENDING_MESSAGES);
+    let overflow = Arc::new(Notify::new());
+    let overflowed = Arc::clone(&overflow);
+    tokio::spawn(async move {
+      let forward = async {
+        while let Some(message) = outbound_rx.recv().await {
+          if let Err(e) = sink.send(message).await {
+            debug!("session {id:?} closed: {e}");
+            break;
+          }
+        }
+      };
+
+      // a client that stopped reading blocks the forwarding on a full
+      // socket, so the overflow signal has to interrupt it.
+      tokio::select! {
+        _ = forward => {},
+        _ = overflowed.notified() => {
+          warn!("session {id:?} is not keeping up with its messages, closing");
+        }
+      }
+    });
+    Self {
+      id,
+      outbound,
+      overflow,
+    }
+  }
+
+  pub fn id(&self) -> SessionId {
+    self.id
+  }
+
+  /// Queues a message to be written to the client socket.
+  ///
+  /// Fails if the underlying connection has been closed. The session is
+  /// closed when the client has too many messages waiting for it.
+  pub fn send(&self, message: Message) -> Result<(), SessionClosed> {
+    match self.outbound.try_send(message) {
+      Ok(()) => Ok(()),
+      Err(TrySendError::Full(_)) => {
+        self.overflow.notify_one();
+        Err(SessionClosed(self.id))
+      }
+      Err(TrySendError::Closed(_)) => Err(SessionClosed(self.id)),
+    }
+  }
+
+  /// Completes once the session can no longer be written to, either
+  /// because the client has gone away or because it fell behind.
+  pub async fn closed(&self) {
+    self.outbound.closed().await
+  }
+
+  /// Pushes a message published on a subscribed topic to the client.
+  pub fn notify(
+    &self,
+    subscription: SubscriptionId,
+    message: &primitives::Message,
+  ) -> Result<(), SessionClosed> {
+    self.send(Message::Text(protocol::subscription(subscription, message)))
+  }
+}
+
+impl Debug for Session {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_tuple("Session").field(&self.id.0).finish()
+  }
+}
@@ -1,4 +1,30 @@
-use {std::path::PathBuf, thiserror::Error};
+use {
+  crate::{
+    network::BanRecord,
+    primitives::{unix_time, Addressable, Message},
+  },
+  libp2p::{Multiaddr, PeerId},
+  multihash::Multihash,
+  serde::{Deserialize, Serialize},
+  sled::{
+    transaction::{
+      ConflictableTransactionError,
+      TransactionError,
+      TransactionalTree,
+    },
+    Transactional,
+  },
+  std::{cmp::Reverse, path::PathBuf, time::Duration},
+  thiserror::Error,
+  tracing::{debug, warn},
+};
+
+/// How often the background sweeper looks for expired mailbox entries.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long messages that were never acknowledged are kept
+/// in the dead-letter tree before they are removed.
+const DEAD_LETTER_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -12,10 +38,504 @@ pub enum Error {
   SystemIO(#[from] std::io::Error),
 }
 
-pub struct PersistentStorage {}
+impl From<TransactionError<Error>> for Error {
+  fn from(error: TransactionError<Error>) -> Self {
+    match error {
+      TransactionError::Abort(e) => e,
+      TransactionError::Storage(e) => Error::StorageEngine(e),
+    }
+  }
+}
+
+/// Aborts a transaction on a serialization error.
+fn abort(error: bincode::Error) -> ConflictableTransactionError<Error> {
+  ConflictableTransactionError::Abort(error.into())
+}
+
+/// A message waiting in the mailbox for its recipient to acknowledge it.
+#[derive(Serialize, Deserialize)]
+struct MailboxEntry {
+  /// Monotonically increasing counter that preserves the
+  /// order in which messages arrived at this node.
+  seq: u64,
+
+  /// Unix timestamp (in seconds) after which the message is removed.
+  expires_at: u64,
+  message: Message,
+}
+
+/// A peer this node was connected to, remembered across restarts so the
+/// node can rejoin the network without relying on bootstrap nodes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KnownPeer {
+  /// Addresses the peer advertised for itself.
+  pub addresses: Vec<Multiaddr>,
+
+  /// Unix timestamp (in seconds) when the peer was last seen active.
+  pub last_seen: u64,
+}
+
+impl KnownPeer {
+  /// A peer that is active right now.
+  pub fn seen_now(addresses: Vec<Multiaddr>) -> Self {
+    Self {
+      addresses,
+      last_seen: unix_time().as_secs(),
+    }
+  }
+
+  /// Time since the peer was last seen active.
+  pub fn age(&self) -> Duration {
+    Duration::from_secs(unix_time().as_secs().saturating_sub(self.last_seen))
+  }
+}
+
+/// The per-node local storage, responsible for storing data that
+/// should survive crashes and restarts.
+///
+/// This type is cheap to clone, all clones refer to the same
+/// underlying database.
+#[derive(Clone)]
+pub struct PersistentStorage {
+  db: sled::Db,
+
+  /// Unacknowledged messages, keyed by `topic ++ message hash`.
+  mailbox: sled::Tree,
+
+  /// Secondary index over the mailbox keyed by
+  /// `expires_at (big endian) ++ mailbox key`, it allows the sweeper
+  /// to find expired messages without scanning the whole mailbox.
+  expiry: sled::Tree,
+
+  /// Secondary index over the mailbox keyed by message hash, it allows
+  /// removing messages acknowledged by other nodes, which only gossip
+  /// the message hash.
+  hashes: sled::Tree,
+
+  /// Messages that were delivered to subscribers on this node, but never
+  /// acknowledged after all redelivery attempts. Keyed like the expiry
+  /// index, by `removed_at (big endian) ++ mailbox key`, so they can be
+  /// swept once they are older than [`DEAD_LETTER_TTL`].
+  dead_letters: sled::Tree,
+
+  /// Peers banned from the p2p network for protocol violations,
+  /// keyed by peer id.
+  bans: sled::Tree,
+
+  /// Address book of peers seen on the p2p network, keyed by peer id.
+  peers: sled::Tree,
+}
 
 impl PersistentStorage {
-  pub fn new(_: PathBuf) -> Result<Self, Error> {
-    Ok(Self {})
+  pub fn new(path: PathBuf) -> Result<Self, Error> {
+    Self::open(sled::Config::new().path(path.join("storage")))
+  }
+
+  fn open(config: sled::Config) -> Result<Self, Error> {
+    let db = config.use_compression(true).open()?;
+
+    let storage = Self {
+      mailbox: db.open_tree("mailbox")?,
+      expiry: db.open_tree("mailbox_expiry")?,
+      hashes: db.open_tree("mailbox_hashes")?,
+      dead_letters: db.open_tree("dead_letters")?,
+      bans: db.open_tree("peer_bans")?,
+      peers: db.open_tree("known_peers")?,
+      db,
+    };
+
+    // periodically remove messages that have outlived their TTL.
+    let sweeper = storage.clone();
+    tokio::spawn(async move {
+      let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+      loop {
+        interval.tick().await;
+        match sweeper.remove_expired_messages() {
+          Ok(0) => {}
+          Ok(count) => debug!("removed {count} expired mailbox messages"),
+          Err(e) => warn!("Failed to sweep expired mailbox messages: {e}"),
+        }
+        match sweeper.remove_expired_dead_letters() {
+          Ok(0) => {}
+          Ok(count) => debug!("removed {count} expired dead letters"),
+          Err(e) => warn!("Failed to sweep expired dead letters: {e}"),
+        }
+      }
+    });
+
+    Ok(storage)
+  }
+
+  /// Stores a message addressed to a topic. The message is kept in the
+  /// mailbox until its recipient acknowledges it, or its TTL runs out.
+  pub fn store_message(&self, message: &Message) -> Result<(), Error> {
+    let hash = message.multihash();
+    let key = mailbox_key(&message.topic, &hash);
+    let expires_at = unix_time().as_secs() + message.ttl as u64;
+
+    // the entry and its index records are written together, so a crash
+    // can't leave behind an entry that the sweeper never finds.
+    (&self.mailbox, &self.expiry, &self.hashes).transaction(
+      |(mailbox, expiry, hashes)| {
+        // the same message stored again keeps its original position in
+        // the arrival order, but its old expiry record is dropped so the
+        // sweeper doesn't remove the refreshed entry early.
+        let seq = match mailbox.get(&key)? {
+          Some(previous) => {
+            let previous: MailboxEntry =
+              bincode::deserialize(&previous).map_err(abort)?;
+            expiry.remove(expiry_key(previous.expires_at, &key))?;
+            previous.seq
+          }
+          None => mailbox.generate_id()?,
+        };
+
+        let entry = MailboxEntry {
+          seq,
+          expires_at,
+          message: message.clone(),
+        };
+        mailbox
+          .insert(key.as_slice(), bincode::serialize(&entry).map_err(abort)?)?;
+        expiry.insert(expiry_key(expires_at, &key), &[])?;
+        hashes.insert(hash.to_bytes(), key.as_slice())?;
+        Ok(())
+      },
+    )?;
+
+    Ok(())
+  }
+
+  /// Lists all messages stored in the mailbox for a topic,
+  /// in the order they have arrived at this node.
+  pub fn messages(&self, topic: &Multihash) -> Result<Vec<Message>, Error> {
+    let mut entries = self
+      .mailbox
+      .scan_prefix(topic.to_bytes())
+      .map(|record| Ok(bincode::deserialize::<MailboxEntry>(&record?.1)?))
+      .collect::<Result<Vec<_>, Error>>()?;
+    entries.sort_by_key(|entry| entry.seq);
+    Ok(entries.into_iter().map(|entry| entry.message).collect())
+  }
+
+  /// Gets a single message from the mailbox, if it is still there.
+  pub fn message(
+    &self,
+    topic: &Multihash,
+    hash: &Multihash,
+  ) -> Result<Option<Message>, Error> {
+    match self.mailbox.get(mailbox_key(topic, hash))? {
+      Some(entry) => {
+        Ok(Some(bincode::deserialize::<MailboxEntry>(&entry)?.message))
+      }
+      None => Ok(None),
+    }
+  }
+
+  /// Moves a message from the mailbox to the dead-letter tree, this
+  /// happens when its recipient never acknowledges it.
+  pub fn dead_letter_message(
+    &self,
+    topic: &Multihash,
+    hash: &Multihash,
+  ) -> Result<(), Error> {
+    let key = mailbox_key(topic, hash);
+    let expires_at = unix_time().as_secs() + DEAD_LETTER_TTL.as_secs();
+    (
+      &self.mailbox,
+      &self.expiry,
+      &self.hashes,
+      &self.dead_letters,
+    )
+      .transaction(|(mailbox, expiry, hashes, dead_letters)| {
+        if let Some(entry) = remove_entry(mailbox, expiry, hashes, &key)? {
+          dead_letters.insert(
+            expiry_key(expires_at, &key),
+            bincode::serialize(&entry.message).map_err(abort)?,
+          )?;
+        }
+        Ok(())
+      })?;
+    Ok(())
+  }
+
+  /// Removes a message from the mailbox, this happens
+  /// when its recipient acknowledges receiving it.
+  pub fn remove_message(
+    &self,
+    topic: &Multihash,
+    hash: &Multihash,
+  ) -> Result<(), Error> {
+    self.remove_entry(&mailbox_key(topic, hash))
+  }
+
+  /// Removes a message from the mailbox when only its hash is known,
+  /// this happens when a recipient on another node acknowledges it.
+  pub fn remove_message_by_hash(&self, hash: &Multihash) -> Result<(), Error> {
+    if let Some(key) = self.hashes.get(hash.to_bytes())? {
+      self.remove_entry(&key)?;
+    }
+    Ok(())
+  }
+
+  /// Removes all messages from the mailbox whose TTL has elapsed,
+  /// and returns the number of removed messages.
+  pub fn remove_expired_messages(&self) -> Result<usize, Error> {
+    self.remove_messages_expired_before(unix_time().as_secs())
+  }
+
+  /// Removes all messages from the mailbox that expire before the given
+  /// unix timestamp, and returns the number of removed messages.
+  fn remove_messages_expired_before(&self, now: u64) -> Result<usize, Error> {
+    let mut count = 0;
+    let cutoff = now.to_be_bytes();
+    for record in self.expiry.range(..cutoff) {
+      let (key, _) = record?;
+      self.remove_entry(&key[8..])?;
+      self.expiry.remove(key)?;
+      count += 1;
+    }
+
+    if count != 0 {
+      self.db.flush()?;
+    }
+
+    Ok(count)
+  }
+
+  /// Removes dead letters that were kept for longer than
+  /// [`DEAD_LETTER_TTL`], and returns the number of removed messages.
+  pub fn remove_expired_dead_letters(&self) -> Result<usize, Error> {
+    self.remove_dead_letters_expired_before(unix_time().as_secs())
+  }
+
+  fn remove_dead_letters_expired_before(
+    &self,
+    now: u64,
+  ) -> Result<usize, Error> {
+    let mut count = 0;
+    for record in self.dead_letters.range(..now.to_be_bytes()) {
+      self.dead_letters.remove(record?.0)?;
+      count += 1;
+    }
+    Ok(count)
+  }
+
+  /// Records a ban imposed on a peer, so it survives restarts.
+  pub fn store_ban(&self, peer: &PeerId, ban: &BanRecord) -> Result<(), Error> {
+    self
+      .bans
+      .insert(peer.to_bytes(), bincode::serialize(ban)?)?;
+    Ok(())
+  }
+
+  pub fn remove_ban(&self, peer: &PeerId) -> Result<(), Error> {
+    self.bans.remove(peer.to_bytes())?;
+    Ok(())
+  }
+
+  /// Lists all recorded peer bans, including expired ones that
+  /// still count towards escalating future bans.
+  pub fn bans(&self) -> Result<Vec<(PeerId, BanRecord)>, Error> {
+    let mut bans = Vec::new();
+    for record in self.bans.iter() {
+      let (key, value) = record?;
+      match PeerId::from_bytes(&key) {
+        Ok(peer) => bans.push((peer, bincode::deserialize(&value)?)),
+        Err(e) => warn!("Skipping ban record with invalid peer id: {e}"),
+      }
+    }
+    Ok(bans)
+  }
+
+  pub fn store_known_peer(
+    &self,
+    peer: &PeerId,
+    record: &KnownPeer,
+  ) -> Result<(), Error> {
+    self
+      .peers
+      .insert(peer.to_bytes(), bincode::serialize(record)?)?;
+    Ok(())
+  }
+
+  pub fn remove_known_peer(&self, peer: &PeerId) -> Result<(), Error> {
+    self.peers.remove(peer.to_bytes())?;
+    Ok(())
+  }
+
+  /// Lists all peers in the address book, most recently seen first.
+  pub fn known_peers(&self) -> Result<Vec<(PeerId, KnownPeer)>, Error> {
+    let mut peers = Vec::new();
+    for record in self.peers.iter() {
+      let (key, value) = record?;
+      match PeerId::from_bytes(&key) {
+        Ok(peer) => {
+          peers.push((peer, bincode::deserialize::<KnownPeer>(&value)?))
+        }
+        Err(e) => warn!("Skipping known peer with invalid peer id: {e}"),
+      }
+    }
+    peers.sort_by_key(|(_, record)| Reverse(record.last_seen));
+    Ok(peers)
+  }
+
+  /// Removes a mailbox entry along with its index records.
+  fn remove_entry(&self, key: &[u8]) -> Result<(), Error> {
+    (&self.mailbox, &self.expiry, &self.hashes).transaction(
+      |(mailbox, expiry, hashes)| remove_entry(mailbox, expiry, hashes, key),
+    )?;
+    Ok(())
+  }
+}
+
+/// Removes a mailbox entry along with its index records
+/// as part of a transaction.
+fn remove_entry(
+  mailbox: &TransactionalTree,
+  expiry: &TransactionalTree,
+  hashes: &TransactionalTree,
+  key: &[u8],
+) -> Result<Option<MailboxEntry>, ConflictableTransactionError<Error>> {
+  let entry = match mailbox.remove(key)? {
+    Some(entry) => {
+      bincode::deserialize::<MailboxEntry>(&entry).map_err(abort)?
+    }
+    None => return Ok(None),
+  };
+  expiry.remove(expiry_key(entry.expires_at, key))?;
+  hashes.remove(entry.message.multihash().to_bytes())?;
+  Ok(Some(entry))
+}
+
+fn mailbox_key(topic: &Multihash, hash: &Multihash) -> Vec<u8> {
+  let mut key = topic.to_bytes();
+  key.extend(hash.to_bytes());
+  key
+}
+
+fn expiry_key(expires_at: u64, mailbox_key: &[u8]) -> Vec<u8> {
+  let mut key = expires_at.to_be_bytes().to_vec();
+  key.extend_from_slice(mailbox_key);
+  key
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::{PersistentStorage, DEAD_LETTER_TTL},
+    crate::primitives::{unix_time, Addressable, Message},
+    multihash::{Code, MultihashDigest},
+  };
+
+  fn storage() -> PersistentStorage {
+    PersistentStorage::open(sled::Config::new().temporary(true)).unwrap()
+  }
+
+  fn message(topic: &str, content: &str, ttl: u32) -> Message {
+    let topic = Code::Sha3_256.digest(topic.as_bytes());
+    Message::new(topic, content.as_bytes().to_vec()).with_ttl(ttl)
+  }
+
+  #[tokio::test]
+  async fn replays_messages_in_arrival_order() {
+    let storage = storage();
+    let messages: Vec<_> = ["third", "first", "second"]
+      .into_iter()
+      .map(|content| message("topic", content, 60))
+      .collect();
+    for message in &messages {
+      storage.store_message(message).unwrap();
+    }
+    storage
+      .store_message(&message("other", "other", 60))
+      .unwrap();
+
+    // storing a message again doesn't move it to the end
+    storage.store_message(&messages[0]).unwrap();
+
+    let topic = messages[0].topic;
+    let stored: Vec<_> = storage
+      .messages(&topic)
+      .unwrap()
+      .iter()
+      .map(Addressable::multihash)
+      .collect();
+    let expected: Vec<_> =
+      messages.iter().map(Addressable::multihash).collect();
+    assert_eq!(stored, expected);
+  }
+
+  #[tokio::test]
+  async fn removes_messages_after_their_ttl() {
+    let storage = storage();
+    let short = message("topic", "short", 10);
+    let long = message("topic", "long", 60);
+    storage.store_message(&short).unwrap();
+    storage.store_message(&long).unwrap();
+
+    let now = unix_time().as_secs();
+    assert_eq!(storage.remove_messages_expired_before(now).unwrap(), 0);
+    assert_eq!(storage.remove_messages_expired_before(now + 30).unwrap(), 1);
+
+    assert!(storage
+      .message(&short.topic, &short.multihash())
+      .unwrap()
+      .is_none());
+    assert!(storage
+      .message(&long.topic, &long.multihash())
+      .unwrap()
+      .is_some());
+    assert!(storage
+      .hashes
+      .get(short.multihash().to_bytes())
+      .unwrap()
+      .is_none());
+    assert_eq!(storage.expiry.len(), 1);
+  }
+
+  #[tokio::test]
+  async fn sweeping_removes_all_index_records() {
+    let storage = storage();
+    let message = message("topic", "content", 10);
+    storage.store_message(&message).unwrap();
+
+    // a refreshed message has only one expiry record
+    storage.store_message(&message).unwrap();
+    assert_eq!(storage.expiry.len(), 1);
+
+    let later = unix_time().as_secs() + 60;
+    assert_eq!(storage.remove_messages_expired_before(later).unwrap(), 1);
+    assert!(storage.mailbox.is_empty());
+    assert!(storage.expiry.is_empty());
+    assert!(storage.hashes.is_empty());
+
+    // nothing left for the next sweep
+    assert_eq!(storage.remove_messages_expired_before(later).unwrap(), 0);
+  }
+
+  #[tokio::test]
+  async fn dead_letters_expire() {
+    let storage = storage();
+    let message = message("topic", "content", 60);
+    storage.store_message(&message).unwrap();
+    storage
+      .dead_letter_message(&message.topic, &message.multihash())
+      .unwrap();
+
+    // moved out of the mailbox along with its index records
+    assert!(storage.mailbox.is_empty());
+    assert!(storage.expiry.is_empty());
+    assert!(storage.hashes.is_empty());
+    assert_eq!(storage.dead_letters.len(), 1);
+
+    let now = unix_time().as_secs();
+    let expired = now + DEAD_LETTER_TTL.as_secs() + 1;
+    assert_eq!(storage.remove_dead_letters_expired_before(now).unwrap(), 0);
+    assert_eq!(
+      storage.remove_dead_letters_expired_before(expired).unwrap(),
+      1
+    );
+    assert!(storage.dead_letters.is_empty());
   }
 }